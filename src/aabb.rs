@@ -0,0 +1,107 @@
+use crate::geometry::Vec3;
+use crate::ray::Ray;
+
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+pub struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Self {
+            min: Vec3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Vec3::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self {
+            min,
+            max,
+        }
+    }
+
+    pub fn min(&self) -> Vec3 {
+        self.min
+    }
+
+    pub fn max(&self) -> Vec3 {
+        self.max
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x() > self.max.x() || self.min.y() > self.max.y() || self.min.z() > self.max.z()
+    }
+
+    pub fn grow(&mut self, p: Vec3) {
+        *self = Aabb::union(*self, Aabb::new(p, p));
+    }
+
+    pub fn union(lhs: Aabb, rhs: Aabb) -> Aabb {
+        Self {
+            min: Vec3::new(
+                lhs.min.x().min(rhs.min.x()),
+                lhs.min.y().min(rhs.min.y()),
+                lhs.min.z().min(rhs.min.z()),
+            ),
+            max: Vec3::new(
+                lhs.max.x().max(rhs.max.x()),
+                lhs.max.y().max(rhs.max.y()),
+                lhs.max.z().max(rhs.max.z()),
+            ),
+        }
+    }
+
+    /// Box after uniform scaling by a positive factor followed by a translation.
+    pub fn transformed(&self, scale: f32, translation: Vec3) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+
+        Aabb::new(scale * self.min + translation, scale * self.max + translation)
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let d = self.max - self.min;
+        2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
+
+    /// Slab test against the box, restricted to the `[t_min, t_max]` range of the ray.
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.intersect(ray, t_min, t_max).is_some()
+    }
+
+    /// Same as `hit`, but also returns the distance at which the ray enters the box.
+    pub fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<f32> {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for axis in 0..3 {
+            let inv_d = 1.0 / ray.direction.raw[axis];
+            let mut t0 = (self.min.raw[axis] - ray.origin.raw[axis]) * inv_d;
+            let mut t1 = (self.max.raw[axis] - ray.origin.raw[axis]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            // Written so that NaNs (ray lying exactly in a slab plane) keep the current range.
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max < t_min {
+                return None;
+            }
+        }
+        Some(t_min)
+    }
+}
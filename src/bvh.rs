@@ -0,0 +1,213 @@
+use crate::hitable::{Hitable, HitRecord};
+use crate::hitable_list::HitableList;
+use crate::ray::Ray;
+use crate::aabb::Aabb;
+
+/// Relative cost of traversing a node compared to intersecting a single primitive.
+const TRAVERSAL_COST: f32 = 0.5;
+
+/// Upper bound on the number of primitives kept in one leaf.
+const MAX_LEAF_SIZE: usize = 4;
+
+pub struct BvhNode {
+    aabb: Aabb,
+    children: Children,
+}
+
+enum Children {
+    Leaf(Vec<Box<dyn Hitable>>),
+    Branch(Box<BvhNode>, Box<BvhNode>),
+}
+
+struct Primitive {
+    hitable: Box<dyn Hitable>,
+    aabb: Aabb,
+    centroid: [f32; 3],
+}
+
+impl BvhNode {
+    pub fn from_list(list: HitableList) -> Self {
+        let primitives = list.into_vec()
+            .into_iter()
+            .map(|hitable| {
+                let aabb = hitable.bounding_box();
+                Primitive { hitable, aabb, centroid: aabb.centroid().raw }
+            })
+            .collect();
+        Self::build(primitives)
+    }
+
+    fn build(mut primitives: Vec<Primitive>) -> Self {
+        let aabb = primitives.iter()
+            .fold(Aabb::default(), |aabb, p| Aabb::union(aabb, p.aabb));
+
+        match best_split(&mut primitives, aabb) {
+            Some((axis, index)) => {
+                sort_by_axis(&mut primitives, axis);
+                let right = primitives.split_off(index);
+                Self {
+                    aabb,
+                    children: Children::Branch(
+                        Box::new(Self::build(primitives)),
+                        Box::new(Self::build(right)),
+                    ),
+                }
+            }
+            None => Self {
+                aabb,
+                children: Children::Leaf(primitives.into_iter().map(|p| p.hitable).collect()),
+            },
+        }
+    }
+}
+
+fn sort_by_axis(primitives: &mut [Primitive], axis: usize) {
+    primitives.sort_by(|a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+}
+
+/// Finds the split with the lowest surface area heuristic cost. Returns the axis along which
+/// primitives have to be sorted and the index of the first primitive of the right child,
+/// or `None` when keeping all primitives in a single leaf is cheaper.
+fn best_split(primitives: &mut [Primitive], aabb: Aabb) -> Option<(usize, usize)> {
+    let n = primitives.len();
+    if n <= 1 {
+        return None;
+    }
+
+    let parent_area = aabb.surface_area();
+    let mut best: Option<(usize, usize)> = None;
+    let mut best_cost = f32::INFINITY;
+    let mut right_areas = vec![0.0; n];
+
+    for axis in 0..3 {
+        sort_by_axis(primitives, axis);
+
+        let mut right = Aabb::default();
+        for i in (1..n).rev() {
+            right = Aabb::union(right, primitives[i].aabb);
+            right_areas[i] = right.surface_area();
+        }
+
+        let mut left = Aabb::default();
+        for i in 1..n {
+            left = Aabb::union(left, primitives[i - 1].aabb);
+            let cost = left.surface_area() * i as f32 + right_areas[i] * (n - i) as f32;
+            if cost < best_cost {
+                best_cost = cost;
+                best = Some((axis, i));
+            }
+        }
+    }
+
+    let leaf_cost = n as f32;
+    let split_cost = if parent_area > 0.0 {
+        TRAVERSAL_COST + best_cost / parent_area
+    } else {
+        TRAVERSAL_COST
+    };
+
+    if n <= MAX_LEAF_SIZE && leaf_cost <= split_cost {
+        None
+    } else {
+        best
+    }
+}
+
+impl Hitable for BvhNode {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        if !self.aabb.hit(ray, t_min, t_max) {
+            return None;
+        }
+
+        match &self.children {
+            Children::Leaf(hitables) => {
+                let mut closest_so_far = t_max;
+                let mut hit_record = None;
+
+                for hitable in hitables {
+                    if let Some(record) = hitable.hit(ray, t_min, closest_so_far) {
+                        closest_so_far = record.t;
+                        hit_record = Some(record);
+                    }
+                }
+
+                hit_record
+            }
+            Children::Branch(left, right) => {
+                let left_hit = left.hit(ray, t_min, t_max);
+                let closest_so_far = left_hit.as_ref().map_or(t_max, |record| record.t);
+                right.hit(ray, t_min, closest_so_far).or(left_hit)
+            }
+        }
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        if !self.aabb.hit(ray, t_min, t_max) {
+            return false;
+        }
+
+        match &self.children {
+            Children::Leaf(hitables) => hitables.iter().any(|hitable| hitable.hit_any(ray, t_min, t_max)),
+            Children::Branch(left, right) => left.hit_any(ray, t_min, t_max) || right.hit_any(ray, t_min, t_max),
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.aabb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Vec3;
+    use crate::material::Lambertian;
+    use crate::sphere::Sphere;
+    use crate::texture::ConstantTexture;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use std::sync::Arc;
+
+    fn random_unit_vector(rng: &mut StdRng) -> Vec3 {
+        loop {
+            let p = Vec3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
+            let length = p.length();
+            if length > 0.01 && length <= 1.0 {
+                return p / length;
+            }
+        }
+    }
+
+    fn random_spheres(seed: u64) -> Vec<Box<dyn Hitable>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let material = Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.5)) });
+        (0..200)
+            .map(|_| {
+                let center = 10.0 * rng.gen::<f32>() * random_unit_vector(&mut rng);
+                let radius = 0.05 + 0.5 * rng.gen::<f32>();
+                Box::new(Sphere { center, radius, material: material.clone() }) as Box<dyn Hitable>
+            })
+            .collect()
+    }
+
+    #[test]
+    fn agrees_with_linear_search() {
+        let list = HitableList::from_vec(random_spheres(1));
+        let bvh = BvhNode::from_list(HitableList::from_vec(random_spheres(1)));
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = 12.0 * random_unit_vector(&mut rng);
+            let target = 8.0 * rng.gen::<f32>() * random_unit_vector(&mut rng);
+            let ray = Ray::new(origin, target - origin);
+            let t_max = 30.0 * rng.gen::<f32>();
+            let expected = list.hit(&ray, 0.001, t_max).map(|record| record.t);
+            let actual = bvh.hit(&ray, 0.001, t_max).map(|record| record.t);
+            assert_eq!(actual, expected);
+            assert_eq!(bvh.hit_any(&ray, 0.001, t_max), expected.is_some());
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 100 && hits < 1900, "{}", hits);
+    }
+}
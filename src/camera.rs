@@ -1,30 +1,541 @@
 use crate::geometry::Vec3;
-use crate::ray::Ray;
+use crate::ray::{Ray, RayCone};
+use crate::hitable::Hitable;
+use crate::rng::{self, random_in_unit_disk};
+use std::f32::consts::PI;
 
-pub(crate) struct Camera {
+/// Maps points of the image to primary rays.
+pub trait Camera: Send + Sync {
+    /// Ray going through the point of the image at (`u`, `v`), where both coordinates
+    /// are in range [0, 1] and (0, 0) is the lower left corner.
+    fn ray(&self, u: f32, v: f32) -> Ray;
+
+    /// Returns the camera with all settings that depend on the size of the image
+    /// or on the rendered world resolved.
+    fn prepare(&self, width: usize, height: usize, world: &dyn Hitable) -> Box<dyn Camera>;
+
+    /// Camera of an eye moved by `offset` to the right, whose view meets the view of the other eye
+    /// at `convergence` distance, which may be infinite for parallel eyes. Used for stereo
+    /// rendering on prepared cameras; projections that cannot converge are only moved.
+    fn eye(&self, offset: f32, convergence: f32) -> Box<dyn Camera>;
+
+    /// Whether the camera renders both eyes of a stereo rig.
+    fn is_stereo(&self) -> bool {
+        false
+    }
+
+    /// Cone of rays that cover a pixel of the given height in image coordinates,
+    /// around the center of the image. Zero when not known, which keeps textures sharp.
+    fn ray_cone(&self, _pixel: f32) -> RayCone {
+        RayCone::default()
+    }
+}
+
+/// Spreads rays of another camera uniformly over the interval between `open` and `close`,
+/// which objects moving over time turn into motion blur.
+pub struct ShutterCamera {
+    camera: Box<dyn Camera>,
+    open: f32,
+    close: f32,
+}
+
+impl ShutterCamera {
+    pub fn new(camera: Box<dyn Camera>, open: f32, close: f32) -> Self {
+        Self {
+            camera,
+            open,
+            close,
+        }
+    }
+}
+
+impl Camera for ShutterCamera {
+    fn ray(&self, u: f32, v: f32) -> Ray {
+        let ray = self.camera.ray(u, v);
+        let time = self.open + rng::random::<f32>() * (self.close - self.open);
+        Ray { time, ..ray }
+    }
+
+    fn prepare(&self, width: usize, height: usize, world: &dyn Hitable) -> Box<dyn Camera> {
+        Box::new(Self { camera: self.camera.prepare(width, height, world), ..*self })
+    }
+
+    fn eye(&self, offset: f32, convergence: f32) -> Box<dyn Camera> {
+        Box::new(Self { camera: self.camera.eye(offset, convergence), ..*self })
+    }
+
+    fn is_stereo(&self) -> bool {
+        self.camera.is_stereo()
+    }
+
+    fn ray_cone(&self, pixel: f32) -> RayCone {
+        self.camera.ray_cone(pixel)
+    }
+}
+
+/// Orthonormal basis of a camera looking from `look_from` at `look_at`,
+/// with `w` pointing backwards.
+fn basis(look_from: Vec3, look_at: Vec3, up: Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = (look_from - look_at).normalize();
+    let u = Vec3::cross(up, w).normalize();
+    let v = Vec3::cross(w, u);
+    (u, v, w)
+}
+
+#[derive(Copy, Clone)]
+pub struct PerspectiveCamera {
     origin: Vec3,
     lower_left_corner: Vec3,
     horizontal: Vec3,
     vertical: Vec3,
+    /// Whether the width of the image plane follows aspect ratio of the rendered image.
+    fit_aspect: bool,
+    /// Zero for a pinhole camera.
+    lens_radius: f32,
+    /// Whether the focus distance should be set to the distance of the object in the center.
+    autofocus: bool,
 }
 
-impl Camera {
-    pub(crate) fn ray(&self, u: f32, v: f32) -> Ray {
-        Ray {
-            origin: self.origin,
-            direction: self.lower_left_corner + u * self.horizontal + v * self.vertical
+impl PerspectiveCamera {
+    pub fn new(origin: Vec3, lower_left_corner: Vec3, horizontal: Vec3, vertical: Vec3) -> Self {
+        Self {
+            origin,
+            lower_left_corner,
+            horizontal,
+            vertical,
+            fit_aspect: false,
+            lens_radius: 0.0,
+            autofocus: false,
+        }
+    }
+
+    /// Camera placed at `look_from` and pointed at `look_at`, with `vfov` being the vertical field
+    /// of view in degrees. When `aspect` is not given, it is taken from the rendered image.
+    pub fn look_at(look_from: Vec3, look_at: Vec3, up: Vec3, vfov: f32, aspect: Option<f32>) -> Self {
+        let half_height = (vfov.to_radians() / 2.0).tan();
+        let half_width = aspect.unwrap_or(1.0) * half_height;
+        let (u, v, w) = basis(look_from, look_at, up);
+
+        Self {
+            origin: look_from,
+            lower_left_corner: look_from - half_width * u - half_height * v - w,
+            horizontal: 2.0 * half_width * u,
+            vertical: 2.0 * half_height * v,
+            fit_aspect: aspect.is_none(),
+            lens_radius: 0.0,
+            autofocus: false,
+        }
+    }
+
+    /// Turns the camera into a thin lens camera with the given lens diameter,
+    /// so that only objects at `focus_distance` from the camera are sharp.
+    pub fn with_lens(self, aperture: f32, focus_distance: f32) -> Self {
+        let current = (self.center() - self.origin).length();
+        let scale = focus_distance / current;
+        Self {
+            lower_left_corner: self.origin + scale * (self.lower_left_corner - self.origin),
+            horizontal: scale * self.horizontal,
+            vertical: scale * self.vertical,
+            lens_radius: aperture / 2.0,
+            autofocus: false,
+            ..self
+        }
+    }
+
+    /// Like `with_lens`, but focused on whatever is visible in the center of the image
+    /// once `focused_on` is called with the scene.
+    pub fn with_autofocus(self, aperture: f32) -> Self {
+        Self {
+            lens_radius: aperture / 2.0,
+            autofocus: true,
+            ..self
+        }
+    }
+
+    /// Resolves the focus distance of an autofocus camera. Cameras with manual focus and those
+    /// that do not see anything in the center are returned unchanged.
+    pub fn focused_on(&self, world: &dyn Hitable) -> Self {
+        if !self.autofocus {
+            return *self;
+        }
+
+        let axis = self.center() - self.origin;
+        match world.hit(&Ray::new(self.origin, axis), 0.001, f32::INFINITY) {
+            Some(record) => self.with_lens(2.0 * self.lens_radius, record.t * axis.length()),
+            None => *self,
+        }
+    }
+
+    fn center(&self) -> Vec3 {
+        self.lower_left_corner + 0.5 * self.horizontal + 0.5 * self.vertical
+    }
+
+    /// Returns the camera adjusted to the given image size. Only the width of the image plane
+    /// changes and only for cameras created without explicit aspect ratio.
+    pub fn fitted_to(&self, width: usize, height: usize) -> Self {
+        if !self.fit_aspect {
+            return *self;
+        }
+
+        let aspect = width as f32 / height as f32;
+        let horizontal = self.horizontal.normalize() * (self.vertical.length() * aspect);
+        Self {
+            lower_left_corner: self.center() - 0.5 * horizontal - 0.5 * self.vertical,
+            horizontal,
+            fit_aspect: false,
+            ..*self
         }
     }
 }
 
+impl Camera for PerspectiveCamera {
+    fn ray(&self, u: f32, v: f32) -> Ray {
+        let offset = if self.lens_radius > 0.0 {
+            let (dx, dy) = random_in_unit_disk();
+            self.lens_radius * (dx * self.horizontal.normalize() + dy * self.vertical.normalize())
+        } else {
+            Vec3::zeros()
+        };
 
-impl Default for Camera {
+        Ray::new(
+            self.origin + offset,
+            self.lower_left_corner + u * self.horizontal + v * self.vertical - self.origin - offset,
+        )
+    }
+
+    fn prepare(&self, width: usize, height: usize, world: &dyn Hitable) -> Box<dyn Camera> {
+        Box::new(self.fitted_to(width, height).focused_on(world))
+    }
+
+    fn eye(&self, offset: f32, convergence: f32) -> Box<dyn Camera> {
+        // Off-axis projection: the image plane is shifted less than the eye, so that both
+        // eyes share the same rectangle at the convergence distance.
+        let distance = (self.center() - self.origin).length();
+        let right = offset * self.horizontal.normalize();
+        Box::new(Self {
+            origin: self.origin + right,
+            lower_left_corner: self.lower_left_corner + (1.0 - distance / convergence) * right,
+            ..*self
+        })
+    }
+
+    fn ray_cone(&self, pixel: f32) -> RayCone {
+        let distance = (self.center() - self.origin).length();
+        RayCone { width: 0.0, spread: pixel * self.vertical.length() / distance }
+    }
+}
+
+impl Default for PerspectiveCamera {
     fn default() -> Self {
+        Self::look_at(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            90.0,
+            None,
+        )
+    }
+}
+
+/// Camera with parallel rays, all of them starting on a rectangle centered at `look_from`.
+#[derive(Copy, Clone)]
+pub struct OrthographicCamera {
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    direction: Vec3,
+    /// Whether the width of the view follows aspect ratio of the rendered image.
+    fit_aspect: bool,
+}
+
+impl OrthographicCamera {
+    /// Camera looking from `look_from` towards `look_at`, covering `height` units vertically.
+    /// When `aspect` is not given, it is taken from the rendered image.
+    pub fn look_at(look_from: Vec3, look_at: Vec3, up: Vec3, height: f32, aspect: Option<f32>) -> Self {
+        let half_height = height / 2.0;
+        let half_width = aspect.unwrap_or(1.0) * half_height;
+        let (u, v, w) = basis(look_from, look_at, up);
+
+        Self {
+            lower_left_corner: look_from - half_width * u - half_height * v,
+            horizontal: 2.0 * half_width * u,
+            vertical: 2.0 * half_height * v,
+            direction: -w,
+            fit_aspect: aspect.is_none(),
+        }
+    }
+
+    /// Returns the camera adjusted to the given image size, see `PerspectiveCamera::fitted_to`.
+    pub fn fitted_to(&self, width: usize, height: usize) -> Self {
+        if !self.fit_aspect {
+            return *self;
+        }
+
+        let aspect = width as f32 / height as f32;
+        let center = self.lower_left_corner + 0.5 * self.horizontal + 0.5 * self.vertical;
+        let horizontal = self.horizontal.normalize() * (self.vertical.length() * aspect);
         Self {
-            origin: Vec3::new(0.0, 0.0, 0.0),
-            lower_left_corner: Vec3::new(-2.0, -1.0, -1.0),
-            horizontal: Vec3::new(4.0, 0.0, 0.0),
-            vertical: Vec3::new(0.0, 2.0, 0.0),
+            lower_left_corner: center - 0.5 * horizontal - 0.5 * self.vertical,
+            horizontal,
+            fit_aspect: false,
+            ..*self
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn ray(&self, u: f32, v: f32) -> Ray {
+        Ray::new(self.lower_left_corner + u * self.horizontal + v * self.vertical, self.direction)
+    }
+
+    fn prepare(&self, width: usize, height: usize, _world: &dyn Hitable) -> Box<dyn Camera> {
+        Box::new(self.fitted_to(width, height))
+    }
+
+    fn eye(&self, offset: f32, _convergence: f32) -> Box<dyn Camera> {
+        Box::new(Self {
+            lower_left_corner: self.lower_left_corner + offset * self.horizontal.normalize(),
+            ..*self
+        })
+    }
+
+    fn ray_cone(&self, pixel: f32) -> RayCone {
+        RayCone { width: pixel * self.vertical.length(), spread: 0.0 }
+    }
+}
+
+/// Equidistant fisheye camera, where the angle between a ray and the viewing direction
+/// grows linearly with the distance from the center of the image. The image circle spanning
+/// `fov` fits the shorter side of the image, pixels outside of it continue the same mapping.
+#[derive(Copy, Clone)]
+pub struct FisheyeCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    /// Field of view of the image circle in radians.
+    fov: f32,
+    /// Width divided by height of the rendered image.
+    aspect: f32,
+}
+
+impl FisheyeCamera {
+    /// Camera placed at `look_from` and pointed at `look_at`, with `fov` in degrees.
+    pub fn look_at(look_from: Vec3, look_at: Vec3, up: Vec3, fov: f32) -> Self {
+        let (u, v, w) = basis(look_from, look_at, up);
+        Self {
+            origin: look_from,
+            u,
+            v,
+            w,
+            fov: fov.to_radians(),
+            aspect: 1.0,
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn ray(&self, u: f32, v: f32) -> Ray {
+        // Coordinates relative to the center, with the image circle having radius of one.
+        let (x, y) = if self.aspect >= 1.0 {
+            (2.0 * (u - 0.5) * self.aspect, 2.0 * (v - 0.5))
+        } else {
+            (2.0 * (u - 0.5), 2.0 * (v - 0.5) / self.aspect)
+        };
+        let theta = (x * x + y * y).sqrt() * self.fov / 2.0;
+        let phi = y.atan2(x);
+
+        let direction = theta.sin() * (phi.cos() * self.u + phi.sin() * self.v) - theta.cos() * self.w;
+        Ray::new(self.origin, direction)
+    }
+
+    fn prepare(&self, width: usize, height: usize, _world: &dyn Hitable) -> Box<dyn Camera> {
+        Box::new(Self { aspect: width as f32 / height as f32, ..*self })
+    }
+
+    fn eye(&self, offset: f32, _convergence: f32) -> Box<dyn Camera> {
+        Box::new(Self { origin: self.origin + offset * self.u, ..*self })
+    }
+
+    fn ray_cone(&self, pixel: f32) -> RayCone {
+        RayCone { width: 0.0, spread: pixel * self.fov / self.aspect.min(1.0) }
+    }
+}
+
+/// Full 360° by 180° panorama in equirectangular projection, with `look_at`
+/// in the center of the image.
+#[derive(Copy, Clone)]
+pub struct EquirectangularCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    /// Radius of the circle the rays start on, signed by the eye, zero for a monoscopic panorama.
+    eye_offset: f32,
+    convergence: f32,
+}
+
+impl EquirectangularCamera {
+    pub fn look_at(look_from: Vec3, look_at: Vec3, up: Vec3) -> Self {
+        let (u, v, w) = basis(look_from, look_at, up);
+        Self { origin: look_from, u, v, w, eye_offset: 0.0, convergence: f32::INFINITY }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn ray(&self, u: f32, v: f32) -> Ray {
+        let longitude = (u - 0.5) * 2.0 * PI;
+        let latitude = (v - 0.5) * PI;
+
+        let direction = latitude.cos() * (longitude.sin() * self.u - longitude.cos() * self.w)
+            + latitude.sin() * self.v;
+        if self.eye_offset == 0.0 {
+            return Ray::new(self.origin, direction);
         }
+
+        // Omni-directional stereo: every column is seen by an eye on a circle around the origin,
+        // moved sideways relative to the direction the column looks at.
+        let offset = self.eye_offset * (longitude.cos() * self.u + longitude.sin() * self.w);
+        Ray::new(self.origin + offset, direction - offset / self.convergence)
+    }
+
+    fn prepare(&self, _width: usize, _height: usize, _world: &dyn Hitable) -> Box<dyn Camera> {
+        Box::new(*self)
+    }
+
+    fn eye(&self, offset: f32, convergence: f32) -> Box<dyn Camera> {
+        Box::new(Self { eye_offset: offset, convergence, ..*self })
+    }
+
+    fn ray_cone(&self, pixel: f32) -> RayCone {
+        RayCone { width: 0.0, spread: pixel * PI }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::sphere::Sphere;
+    use crate::texture::ConstantTexture;
+    use std::sync::Arc;
+
+    fn angle(a: Vec3, b: Vec3) -> f32 {
+        Vec3::cross(a, b).length().atan2(Vec3::dot(a, b)).to_degrees()
+    }
+
+    #[test]
+    fn center_ray_points_at_look_at() {
+        let look_from = Vec3::new(1.0, 2.0, 3.0);
+        let look_at = Vec3::new(4.0, 0.0, -2.0);
+        let camera = PerspectiveCamera::look_at(look_from, look_at, Vec3::new(0.0, 1.0, 0.0), 40.0, Some(1.5));
+        let ray = camera.ray(0.5, 0.5);
+        assert!((ray.origin - look_from).length() < 1e-6);
+        assert!(angle(ray.direction, look_at - look_from) < 0.01);
+        // Top and bottom edges are half of the field of view away from the center.
+        assert!((angle(camera.ray(0.5, 1.0).direction, look_at - look_from) - 20.0).abs() < 0.01);
+        assert!((angle(camera.ray(0.5, 0.0).direction, look_at - look_from) - 20.0).abs() < 0.01);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn fitting_aspect_keeps_vertical_field_of_view() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let camera = PerspectiveCamera::look_at(Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0), up, 60.0, None)
+            .fitted_to(200, 100);
+        let axis = Vec3::new(0.0, 0.0, -1.0);
+        assert!((angle(camera.ray(0.5, 1.0).direction, axis) - 30.0).abs() < 0.01);
+        let half_width = camera.ray(1.0, 0.5).direction.x() / -camera.ray(1.0, 0.5).direction.z();
+        assert!((half_width - 2.0 * 30.0f32.to_radians().tan()).abs() < 1e-5);
+
+        // An explicit aspect ratio is not changed by the image size.
+        let square = PerspectiveCamera::look_at(Vec3::zeros(), axis, up, 60.0, Some(1.0)).fitted_to(200, 100);
+        assert!((angle(square.ray(1.0, 0.5).direction, axis) - 30.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel_and_start_on_view_rectangle() {
+        let look_from = Vec3::new(0.0, 0.0, 5.0);
+        let camera = OrthographicCamera::look_at(look_from, Vec3::zeros(), Vec3::new(0.0, 1.0, 0.0), 2.0, Some(2.0));
+        let center = camera.ray(0.5, 0.5);
+        assert!((center.origin - look_from).length() < 1e-6);
+        assert!(angle(center.direction, Vec3::new(0.0, 0.0, -1.0)) < 0.01);
+
+        let corner = camera.ray(0.0, 0.0);
+        assert!((corner.origin - Vec3::new(-2.0, -1.0, 5.0)).length() < 1e-6);
+        assert!(angle(corner.direction, center.direction) < 0.01);
+        let corner = camera.ray(1.0, 1.0);
+        assert!((corner.origin - Vec3::new(2.0, 1.0, 5.0)).length() < 1e-6);
+        assert!(angle(corner.direction, center.direction) < 0.01);
+    }
+
+    #[test]
+    fn fisheye_angle_grows_linearly_from_center() {
+        let axis = Vec3::new(0.0, 0.0, -1.0);
+        let camera = FisheyeCamera::look_at(Vec3::zeros(), axis, Vec3::new(0.0, 1.0, 0.0), 120.0);
+        assert!(angle(camera.ray(0.5, 0.5).direction, axis) < 0.01);
+        assert!((angle(camera.ray(1.0, 0.5).direction, axis) - 60.0).abs() < 0.01);
+        assert!((angle(camera.ray(0.5, 0.0).direction, axis) - 60.0).abs() < 0.01);
+        assert!((angle(camera.ray(0.75, 0.5).direction, axis) - 30.0).abs() < 0.01);
+        // Right of the center is right of the view.
+        assert!(camera.ray(1.0, 0.5).direction.x() > 0.0);
+
+        // The image circle fits the shorter side of a wide image.
+        let wide = FisheyeCamera { aspect: 2.0, ..camera };
+        assert!((angle(wide.ray(0.5, 1.0).direction, axis) - 60.0).abs() < 0.01);
+        assert!((angle(wide.ray(0.75, 0.5).direction, axis) - 60.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn equirectangular_maps_longitude_and_latitude() {
+        let camera = EquirectangularCamera::look_at(Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0));
+        let towards = |u, v, direction: [f32; 3]| {
+            let ray = camera.ray(u, v);
+            assert!((ray.origin - Vec3::zeros()).length() < 1e-6);
+            assert!(angle(ray.direction, Vec3::new(direction[0], direction[1], direction[2])) < 0.01);
+        };
+        towards(0.5, 0.5, [0.0, 0.0, -1.0]);
+        towards(0.75, 0.5, [1.0, 0.0, 0.0]);
+        towards(0.25, 0.5, [-1.0, 0.0, 0.0]);
+        towards(0.0, 0.5, [0.0, 0.0, 1.0]);
+        towards(0.5, 1.0, [0.0, 1.0, 0.0]);
+        towards(0.5, 0.0, [0.0, -1.0, 0.0]);
+        towards(0.625, 0.75, [0.5, 0.5f32.sqrt(), -0.5]);
+    }
+
+    #[test]
+    fn shutter_spreads_ray_times_over_interval() {
+        rng::seed(13);
+        let lens = PerspectiveCamera::look_at(Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 40.0, Some(1.0));
+        let camera = ShutterCamera::new(Box::new(lens), 0.25, 0.75);
+        let times: Vec<f32> = (0..1000).map(|_| camera.ray(0.5, 0.5).time).collect();
+        assert!(times.iter().all(|&t| (0.25..=0.75).contains(&t)));
+        assert!(times.iter().any(|&t| t < 0.3) && times.iter().any(|&t| t > 0.7));
+        // Directions are those of the wrapped camera.
+        assert!(angle(camera.ray(0.5, 0.5).direction, lens.ray(0.5, 0.5).direction) < 0.01);
+    }
+
+    #[test]
+    fn autofocus_uses_distance_of_hit() {
+        let sphere = Sphere {
+            center: Vec3::new(0.0, 0.0, -5.0),
+            radius: 1.0,
+            material: Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.5)) }),
+        };
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let camera = PerspectiveCamera::look_at(Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0), up, 60.0, Some(1.0))
+            .with_autofocus(0.5)
+            .focused_on(&sphere);
+        assert!(((camera.center() - camera.origin).length() - 4.0).abs() < 1e-4);
+        // Rays through the center of the image meet at the focused point wherever they leave the lens.
+        for _ in 0..10 {
+            let ray = camera.ray(0.5, 0.5);
+            assert!((ray.point_at_parameter(1.0) - Vec3::new(0.0, 0.0, -4.0)).length() < 1e-4);
+        }
+
+        // Nothing in the center keeps the focus distance.
+        let empty = PerspectiveCamera::look_at(Vec3::zeros(), Vec3::new(0.0, 0.0, 1.0), up, 60.0, Some(1.0))
+            .with_autofocus(0.5)
+            .focused_on(&sphere);
+        assert!(((empty.center() - empty.origin).length() - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,435 @@
+use crate::geometry::Vec3;
+use crate::image::Image;
+use crate::rng::{self, random_unit_vector};
+use std::f32::consts::PI;
+
+/// Radiance arriving from infinitely far away, seen by rays that miss the world.
+pub trait Environment: Send + Sync {
+    /// Radiance arriving from the unit `direction`.
+    fn radiance(&self, direction: Vec3) -> Vec3;
+
+    /// Chooses a direction towards the environment together with its probability density
+    /// with respect to solid angle. Directions are uniform unless overridden.
+    fn sample(&self) -> (Vec3, f32) {
+        (random_unit_vector(), 1.0 / (4.0 * PI))
+    }
+
+    /// Probability density of `sample` choosing the unit `direction`.
+    fn pdf(&self, _direction: Vec3) -> f32 {
+        1.0 / (4.0 * PI)
+    }
+}
+
+/// The same radiance from every direction.
+pub struct ConstantEnvironment {
+    pub radiance: Vec3,
+}
+
+impl Environment for ConstantEnvironment {
+    fn radiance(&self, _direction: Vec3) -> Vec3 {
+        self.radiance
+    }
+}
+
+/// Sky blending from `bottom` straight down to `top` straight up.
+pub struct GradientEnvironment {
+    pub bottom: Vec3,
+    pub top: Vec3,
+}
+
+impl Default for GradientEnvironment {
+    fn default() -> Self {
+        Self {
+            bottom: Vec3::new(1.0, 1.0, 1.0),
+            top: Vec3::new(0.5, 0.7, 1.0),
+        }
+    }
+}
+
+impl Environment for GradientEnvironment {
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let t = 0.5 * (direction.y() + 1.0);
+        (1.0 - t) * self.bottom + t * self.top
+    }
+}
+
+/// Equirectangular image wrapped around the scene, with the middle of the image towards -z
+/// and its top row straight up. Directions are sampled in proportion to luminance of the pixels,
+/// so that small bright regions like the sun are found by shadow rays.
+pub struct ImageEnvironment {
+    image: Image,
+    /// Rotation about the y axis, in radians.
+    rotation: f32,
+    intensity: f32,
+    /// Running sums of weights of the rows.
+    rows: Vec<f32>,
+    /// Running sums of weights of pixels within each row, row by row.
+    columns: Vec<f32>,
+}
+
+impl ImageEnvironment {
+    pub fn new(image: Image, rotation: f32, intensity: f32) -> Self {
+        let (width, height) = (image.width(), image.height());
+        let mut rows = Vec::with_capacity(height);
+        let mut columns = Vec::with_capacity(width * height);
+        let mut total = 0.0;
+        for y in 0..height {
+            // Rows near the poles cover less solid angle, which sin(theta) accounts for.
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            let mut row_total = 0.0;
+            for x in 0..width {
+                row_total += luminance(image.pixel(x, y)).max(0.0) * sin_theta;
+                columns.push(row_total);
+            }
+            total += row_total;
+            rows.push(total);
+        }
+
+        Self {
+            image,
+            rotation,
+            intensity,
+            rows,
+            columns,
+        }
+    }
+
+    fn total(&self) -> f32 {
+        self.rows.last().copied().unwrap_or(0.0)
+    }
+
+    /// Position in the image of the unit `direction`, both coordinates in [0, 1).
+    fn position_of(&self, direction: Vec3) -> (f32, f32) {
+        let phi = direction.x().atan2(-direction.z()) - self.rotation;
+        let u = (0.5 + phi / (2.0 * PI)).rem_euclid(1.0);
+        let v = direction.y().clamp(-1.0, 1.0).acos() / PI;
+        (u.min(1.0 - f32::EPSILON), v.min(1.0 - f32::EPSILON))
+    }
+
+    fn direction_at(&self, u: f32, v: f32) -> Vec3 {
+        let phi = (u - 0.5) * 2.0 * PI + self.rotation;
+        let theta = v * PI;
+        Vec3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+    }
+
+    fn texel(&self, u: f32, v: f32) -> (usize, usize) {
+        let x = ((u * self.image.width() as f32) as usize).min(self.image.width() - 1);
+        let y = ((v * self.image.height() as f32) as usize).min(self.image.height() - 1);
+        (x, y)
+    }
+
+    /// Density of choosing the pixel in the given column and row, with respect to area of the image
+    /// in [0, 1] coordinates.
+    fn image_pdf(&self, x: usize, y: usize) -> f32 {
+        let width = self.image.width();
+        let previous = if x > 0 { self.columns[y * width + x - 1] } else { 0.0 };
+        let weight = self.columns[y * width + x] - previous;
+        weight * (width * self.image.height()) as f32 / self.total()
+    }
+}
+
+impl Environment for ImageEnvironment {
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let (u, v) = self.position_of(direction);
+        let (x, y) = self.texel(u, v);
+        self.intensity * self.image.pixel(x, y)
+    }
+
+    fn sample(&self) -> (Vec3, f32) {
+        let total = self.total();
+        if !is_usable(total) {
+            return (random_unit_vector(), 1.0 / (4.0 * PI));
+        }
+
+        // Pixels without weight are never chosen, as their running sum equals that of the previous one.
+        let (width, height) = (self.image.width(), self.image.height());
+        let target = rng::random::<f32>() * total;
+        let y = self.rows.partition_point(|&sum| sum <= target).min(height - 1);
+        let row = &self.columns[y * width..(y + 1) * width];
+        let target = rng::random::<f32>() * row[width - 1];
+        let x = row.partition_point(|&sum| sum <= target).min(width - 1);
+
+        let u = (x as f32 + rng::random::<f32>()) / width as f32;
+        let v = (y as f32 + rng::random::<f32>()) / height as f32;
+        let direction = self.direction_at(u, v);
+        (direction, self.pdf(direction))
+    }
+
+    fn pdf(&self, direction: Vec3) -> f32 {
+        if !is_usable(self.total()) {
+            return 1.0 / (4.0 * PI);
+        }
+
+        let (u, v) = self.position_of(direction);
+        // Taken from the direction itself, as acos loses precision close to the poles.
+        let sin_theta = (direction.x() * direction.x() + direction.z() * direction.z()).sqrt();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let (x, y) = self.texel(u, v);
+        // The image maps to the sphere with 2pi^2 sin(theta) of solid angle per unit of area.
+        self.image_pdf(x, y) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+/// Angular radius of the sun as seen from the earth, in radians.
+const SUN_ANGULAR_RADIUS: f32 = 0.004_65;
+
+/// Luminance of the sun above the atmosphere, in kcd/m².
+const SUN_LUMINANCE: f32 = 2.0e6;
+
+/// Scene radiance per kcd/m² of luminance, chosen so that a white surface facing the sun
+/// at noon is close to one.
+const SKY_SCALE: f32 = 0.03;
+
+/// Daylight sky of Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight",
+/// together with the sun disk and the ground below the horizon. Directions are sampled
+/// towards the sun half of the time, so that sunlight is found by shadow rays.
+pub struct SunSkyEnvironment {
+    sun_direction: Vec3,
+    /// Perez coefficients of luminance and both chromaticity coordinates.
+    perez: [[f32; 5]; 3],
+    /// Luminance and chromaticity of the zenith, divided by the Perez function there.
+    zenith: [f32; 3],
+    /// Radiance of the sun disk, zero when the sun is below the horizon.
+    sun_radiance: Vec3,
+    /// One minus the cosine of the angular radius of the sun.
+    sun_cone: f32,
+    ground_radiance: Vec3,
+    intensity: f32,
+}
+
+impl SunSkyEnvironment {
+    /// Sky lit by the sun in `sun_direction`, with `turbidity` from 2 for a very clear sky
+    /// to 10 for a hazy one. The ground reflects the light of the sky and sun with `ground_albedo`.
+    pub fn new(sun_direction: Vec3, turbidity: f32, ground_albedo: Vec3, intensity: f32) -> Self {
+        let sun_direction = sun_direction.normalize();
+        let t = turbidity;
+        // The model holds only for the sun above the horizon.
+        let sun_zenith = sun_direction.y().clamp(-1.0, 1.0).acos().min(0.5 * PI - 0.01);
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * sun_zenith);
+        let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (t2, th, th2, th3) = (t * t, sun_zenith, sun_zenith * sun_zenith, sun_zenith.powi(3));
+        let x = t2 * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th)
+            + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394)
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let y = t2 * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th)
+            + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516)
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+        let mut zenith = [luminance, x, y];
+        for (value, coefficients) in zenith.iter_mut().zip(perez.iter()) {
+            *value /= perez_function(coefficients, 0.0, sun_zenith);
+        }
+
+        let sun_cone = 2.0 * (0.5 * SUN_ANGULAR_RADIUS).sin().powi(2);
+        let mut sky = Self {
+            sun_direction,
+            perez,
+            zenith,
+            sun_radiance: sun_radiance(sun_direction, turbidity),
+            sun_cone,
+            ground_radiance: Vec3::zeros(),
+            intensity,
+        };
+
+        // Lambertian ground lit by the whole sky and the sun.
+        let (rows, columns) = (32, 128);
+        let mut irradiance = Vec3::zeros();
+        for i in 0..rows {
+            let theta = 0.5 * PI * (i as f32 + 0.5) / rows as f32;
+            let solid_angle = theta.sin() * (0.5 * PI / rows as f32) * (2.0 * PI / columns as f32);
+            for j in 0..columns {
+                let phi = 2.0 * PI * (j as f32 + 0.5) / columns as f32;
+                let direction = Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                irradiance = irradiance + (theta.cos() * solid_angle) * sky.sky_radiance(direction);
+            }
+        }
+        let sun_solid_angle = 2.0 * PI * sun_cone;
+        irradiance = irradiance + (sun_direction.y().max(0.0) * sun_solid_angle) * sky.sun_radiance;
+        sky.ground_radiance = ground_albedo * irradiance / PI;
+        sky
+    }
+
+    /// Radiance of the sky without the sun disk, for directions above the horizon.
+    fn sky_radiance(&self, direction: Vec3) -> Vec3 {
+        let theta = direction.y().clamp(0.0, 1.0).acos();
+        let gamma = Vec3::dot(direction, self.sun_direction).clamp(-1.0, 1.0).acos();
+        let mut values = [0.0; 3];
+        for (value, (coefficients, zenith)) in values.iter_mut().zip(self.perez.iter().zip(self.zenith.iter())) {
+            *value = zenith * perez_function(coefficients, theta, gamma);
+        }
+        let [luminance, x, y] = values;
+        SKY_SCALE * xyy_to_rgb(x, y, luminance)
+    }
+
+    fn in_sun(&self, direction: Vec3) -> bool {
+        // Chord length between unit vectors stays accurate for the tiny cone of the sun.
+        (direction - self.sun_direction).squared_len() <= 2.0 * self.sun_cone
+    }
+
+    fn sun_probability(&self) -> f32 {
+        if self.sun_radiance.squared_len() > 0.0 { 0.5 } else { 0.0 }
+    }
+}
+
+impl Environment for SunSkyEnvironment {
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let radiance = if direction.y() < 0.0 {
+            self.ground_radiance
+        } else if self.in_sun(direction) {
+            self.sky_radiance(direction) + self.sun_radiance
+        } else {
+            self.sky_radiance(direction)
+        };
+        self.intensity * radiance
+    }
+
+    fn sample(&self) -> (Vec3, f32) {
+        if rng::random::<f32>() >= self.sun_probability() {
+            let direction = random_unit_vector();
+            return (direction, self.pdf(direction));
+        }
+
+        // Uniform within the cone of the sun, with 1 - cos(theta) kept exact.
+        let one_minus_cos = rng::random::<f32>() * self.sun_cone;
+        let sin_theta = (one_minus_cos * (2.0 - one_minus_cos)).sqrt();
+        let phi = 2.0 * PI * rng::random::<f32>();
+        let (tangent, bitangent) = self.sun_direction.orthonormal_basis();
+        let direction = (1.0 - one_minus_cos) * self.sun_direction
+            + sin_theta * phi.cos() * tangent
+            + sin_theta * phi.sin() * bitangent;
+        let direction = direction.normalize();
+        (direction, self.pdf(direction))
+    }
+
+    fn pdf(&self, direction: Vec3) -> f32 {
+        let sun = self.sun_probability();
+        let mut pdf = (1.0 - sun) / (4.0 * PI);
+        if sun > 0.0 && self.in_sun(direction) {
+            pdf += sun / (2.0 * PI * self.sun_cone);
+        }
+        pdf
+    }
+}
+
+/// Relative distribution of the sky over zenith angle `theta` and angle `gamma` from the sun.
+fn perez_function([a, b, c, d, e]: &[f32; 5], theta: f32, gamma: f32) -> f32 {
+    let cos_theta = theta.cos().max(0.01);
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+/// Radiance of the sun attenuated by Rayleigh and aerosol scattering along its path
+/// through the atmosphere, evaluated at representative wavelengths of the red, green
+/// and blue primaries.
+fn sun_radiance(sun_direction: Vec3, turbidity: f32) -> Vec3 {
+    if sun_direction.y() <= 0.0 {
+        return Vec3::zeros();
+    }
+    let zenith_degrees = sun_direction.y().acos().to_degrees();
+    let air_mass = 1.0 / (sun_direction.y() + 0.15 * (93.885 - zenith_degrees).powf(-1.253));
+    let beta = 0.046_08 * turbidity - 0.045_86;
+    let transmittance = |wavelength: f32| {
+        let rayleigh = (-0.008_735 * wavelength.powf(-4.08) * air_mass).exp();
+        let aerosol = (-beta * wavelength.powf(-1.3) * air_mass).exp();
+        rayleigh * aerosol
+    };
+    SKY_SCALE * SUN_LUMINANCE * Vec3::new(transmittance(0.68), transmittance(0.55), transmittance(0.44))
+}
+
+/// Converts CIE xyY chromaticity and luminance to linear sRGB.
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Vec3 {
+    if y <= 0.0 {
+        return Vec3::zeros();
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    Vec3::new(
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0),
+    )
+}
+
+/// Whether the total weight of an image can drive sampling, otherwise directions are uniform.
+fn is_usable(total: f32) -> bool {
+    total > 0.0 && total.is_finite()
+}
+
+fn luminance(c: Vec3) -> f32 {
+    0.2126 * c.r() + 0.7152 * c.g() + 0.0722 * c.b()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sun_image() -> Image {
+        let (width, height) = (32, 16);
+        let pixels = (0..width * height)
+            .map(|i| if i == 5 * width + 20 { Vec3::new(1000.0, 900.0, 800.0) } else { Vec3::new(0.2, 0.3, 0.5) })
+            .collect();
+        Image::from_pixels(width, height, pixels)
+    }
+
+    #[test]
+    fn image_directions_round_trip() {
+        let environment = ImageEnvironment::new(sun_image(), 0.7, 1.0);
+        for &(u, v) in &[(0.1, 0.2), (0.5, 0.5), (0.93, 0.71)] {
+            let (u2, v2) = environment.position_of(environment.direction_at(u, v));
+            assert!((u - u2).abs() < 1e-4 && (v - v2).abs() < 1e-4, "{} {} {} {}", u, v, u2, v2);
+        }
+        let forward = ImageEnvironment::new(sun_image(), 0.0, 1.0).position_of(Vec3::new(0.0, 0.0, -1.0));
+        assert!((forward.0 - 0.5).abs() < 1e-6 && (forward.1 - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn samples_favor_the_sun_and_match_pdf() {
+        let environment = ImageEnvironment::new(sun_image(), 0.3, 2.0);
+        let count = 20_000;
+        let mut solid_angle = 0.0;
+        let mut sun = 0;
+        for _ in 0..count {
+            let (direction, pdf) = environment.sample();
+            assert!((direction.length() - 1.0).abs() < 1e-4);
+            assert!((pdf - environment.pdf(direction)).abs() <= 1e-3 * pdf);
+            solid_angle += 1.0 / pdf / count as f32;
+            if environment.radiance(direction).r() > 1000.0 {
+                sun += 1;
+            }
+        }
+        assert!((solid_angle - 4.0 * PI).abs() < 0.05 * 4.0 * PI, "{}", solid_angle);
+        assert!(sun > count / 2, "{}", sun);
+    }
+
+    #[test]
+    fn sky_is_brightest_towards_the_sun_and_samples_match_pdf() {
+        let sun_direction = Vec3::new(0.3, 0.6, -0.5).normalize();
+        let sky = SunSkyEnvironment::new(sun_direction, 3.0, Vec3::new(0.3, 0.3, 0.3), 1.0);
+
+        // A clear sky is blue overhead, and the sun outshines it by orders of magnitude.
+        let zenith = sky.radiance(Vec3::new(0.0, 1.0, 0.0));
+        assert!(zenith.b() > zenith.r(), "{:?}", zenith);
+        assert!(sky.radiance(sun_direction).g() > 1000.0 * zenith.g());
+        let ground = sky.radiance(Vec3::new(0.0, -1.0, 0.0));
+        assert!(ground.g() > 0.0 && ground.g() < sky.radiance(sun_direction).g());
+
+        let count = 20_000;
+        let mut solid_angle = 0.0;
+        for _ in 0..count {
+            let (direction, pdf) = sky.sample();
+            assert!((direction.length() - 1.0).abs() < 1e-4);
+            assert_eq!(pdf, sky.pdf(direction));
+            if !sky.in_sun(direction) {
+                solid_angle += 1.0 / pdf / count as f32;
+            }
+        }
+        assert!((solid_angle - 4.0 * PI).abs() < 0.05 * 4.0 * PI, "{}", solid_angle);
+    }
+}
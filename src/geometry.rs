@@ -1,48 +1,62 @@
 #[derive(Copy, Clone)]
-pub(crate) struct Vec3 {
-    pub(crate) raw: [f32; 3],
+#[derive(Debug)]
+pub struct Vec3 {
+    pub raw: [f32; 3],
 }
 
 impl Vec3 {
-    pub(crate) fn new(x: f32, y: f32, z: f32) -> Self {
+    pub fn new(x: f32, y: f32, z: f32) -> Self {
         Self {
             raw: [x, y, z],
         }
     }
 
-    pub(crate) fn zeros() -> Self {
+    pub fn zeros() -> Self {
         Self {
             raw: [0.0; 3],
         }
     }
 
-    pub(crate) fn x(&self) -> f32 { self.raw[0] }
-    pub(crate) fn y(&self) -> f32 { self.raw[1] }
-    pub(crate) fn z(&self) -> f32 { self.raw[2] }
+    pub fn x(&self) -> f32 { self.raw[0] }
+    pub fn y(&self) -> f32 { self.raw[1] }
+    pub fn z(&self) -> f32 { self.raw[2] }
 
-    pub(crate) fn r(&self) -> f32 { self.raw[0] }
-    pub(crate) fn g(&self) -> f32 { self.raw[1] }
-    pub(crate) fn b(&self) -> f32 { self.raw[2] }
+    pub fn r(&self) -> f32 { self.raw[0] }
+    pub fn g(&self) -> f32 { self.raw[1] }
+    pub fn b(&self) -> f32 { self.raw[2] }
 
 
-    pub(crate) fn squared_len(&self) -> f32 {
+    pub fn squared_len(&self) -> f32 {
         let [x, y, z] = self.raw;
-        (x * x + y * y + z * z).sqrt()
+        x * x + y * y + z * z
     }
 
-    pub(crate) fn length(&self) -> f32 {
+    pub fn length(&self) -> f32 {
         self.squared_len().sqrt()
     }
 
-    pub(crate) fn normalize(&self) -> Vec3 {
+    pub fn normalize(&self) -> Vec3 {
         *self * (1.0 / self.length())
     }
 
-    pub(crate) fn dot(lhs: Vec3, rhs: Vec3) -> f32 {
+    /// Two unit vectors that together with this unit vector form an orthonormal basis
+    /// (Duff et al., "Building an Orthonormal Basis, Revisited").
+    pub fn orthonormal_basis(&self) -> (Vec3, Vec3) {
+        let [x, y, z] = self.raw;
+        let sign = 1.0f32.copysign(z);
+        let a = -1.0 / (sign + z);
+        let b = x * y * a;
+        (
+            Vec3::new(1.0 + sign * x * x * a, sign * b, -sign * x),
+            Vec3::new(b, sign + y * y * a, -y),
+        )
+    }
+
+    pub fn dot(lhs: Vec3, rhs: Vec3) -> f32 {
         lhs.x() * rhs.x() + lhs.y() * rhs.y() + lhs.z() * rhs.z()
     }
 
-    pub(crate) fn cross(lhs: Vec3, rhs: Vec3) -> Vec3 {
+    pub fn cross(lhs: Vec3, rhs: Vec3) -> Vec3 {
         Vec3::new(
             lhs.y() * rhs.z() - lhs.z() * rhs.y(),
             lhs.z() * rhs.x() - lhs.x() * rhs.z(),
@@ -125,4 +139,17 @@ impl ops::Neg for Vec3 {
     fn neg(self) -> Self::Output {
         Vec3::new(-self.x(), -self.y(), -self.z())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths() {
+        let v = Vec3::new(2.0, 3.0, 6.0);
+        assert_eq!(v.squared_len(), 49.0);
+        assert_eq!(v.length(), 7.0);
+        assert!((v.normalize().length() - 1.0).abs() < 1e-6);
+    }
+}
@@ -1,16 +1,31 @@
 use crate::ray::Ray;
 use crate::geometry::Vec3;
 use crate::material::Material;
+use crate::aabb::Aabb;
 use std::sync::Arc;
 
 #[derive(Clone)]
-pub(crate) struct HitRecord {
-    pub(crate) t: f32,
-    pub(crate) point: Vec3,
-    pub(crate) normal: Vec3,
-    pub(crate) material: Arc<dyn Material>,
+pub struct HitRecord {
+    pub t: f32,
+    pub point: Vec3,
+    pub normal: Vec3,
+    /// Texture coordinates of the hit point.
+    pub u: f32,
+    pub v: f32,
+    /// Width of the area seen by the cone of the ray, in texture coordinates.
+    pub footprint: f32,
+    pub material: Arc<dyn Material>,
 }
 
-pub(crate) trait Hitable: Send+Sync {
+pub trait Hitable: Send+Sync {
+    /// Finds the closest intersection with the ray within `(t_min, t_max)`.
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+
+    /// Checks whether the ray intersects anything within `(t_min, t_max)`, which is all
+    /// shadow and occlusion rays need. Implementations may stop at any hit, not the closest one.
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.hit(ray, t_min, t_max).is_some()
+    }
+
+    fn bounding_box(&self) -> Aabb;
 }
\ No newline at end of file
@@ -1,16 +1,21 @@
 use crate::hitable::{Hitable, HitRecord};
 use crate::ray::Ray;
+use crate::aabb::Aabb;
 
-pub(crate) struct HitableList {
+pub struct HitableList {
     hitables: Vec<Box<dyn Hitable>>,
 }
 
 impl HitableList {
-    pub(crate) fn from_vec(hitables: Vec<Box<dyn Hitable>>) -> Self {
+    pub fn from_vec(hitables: Vec<Box<dyn Hitable>>) -> Self {
         Self {
             hitables
         }
     }
+
+    pub fn into_vec(self) -> Vec<Box<dyn Hitable>> {
+        self.hitables
+    }
 }
 
 impl Hitable for HitableList {
@@ -27,4 +32,14 @@ impl Hitable for HitableList {
 
         hit_record
     }
-}
\ No newline at end of file
+
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.hitables.iter().any(|hitable| hitable.hit_any(ray, t_min, t_max))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.hitables
+            .iter()
+            .fold(Aabb::default(), |aabb, hitable| Aabb::union(aabb, hitable.bounding_box()))
+    }
+}
@@ -0,0 +1,531 @@
+use crate::geometry::Vec3;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    /// Plain text 8-bit PPM encoded as sRGB.
+    Ppm,
+    /// Binary 8-bit PPM encoded as sRGB.
+    PpmBinary,
+    /// Portable float map with linear radiance values.
+    Pfm,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "ppm" => Some(ImageFormat::Ppm),
+            "ppm-binary" => Some(ImageFormat::PpmBinary),
+            "pfm" => Some(ImageFormat::Pfm),
+            _ => None,
+        }
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<ImageFormat> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        ImageFormat::from_name(&extension)
+    }
+}
+
+/// Linear radiance values of a rendered frame or a loaded environment, stored row by row from the top.
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl Image {
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<Vec3>) -> Self {
+        assert_eq!(pixels.len(), width * height);
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    /// Pixel in the given column and row, counting rows from the top.
+    pub fn pixel(&self, x: usize, y: usize) -> Vec3 {
+        self.pixels[y * self.width + x]
+    }
+
+    /// Loads a Radiance RGBE (`.hdr`) image, either uncompressed or run-length encoded.
+    /// Only the standard orientation with rows stored from the top is supported.
+    pub fn load_hdr<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+        let mut reader = io::BufReader::new(std::fs::File::open(path)?);
+        read_hdr(&mut reader)
+    }
+
+    /// Loads a PNG, JPEG, PPM, PGM or Radiance HDR image, chosen by the extension of `path`.
+    /// Integer samples are scaled to [0, 1] and, when `srgb` is set, decoded from sRGB,
+    /// in which colors of photographs are stored. Data like roughness is usually stored as is.
+    /// Radiance images are always linear. Alpha channels are ignored.
+    pub fn load<P: AsRef<Path>>(path: P, srgb: bool) -> io::Result<Image> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+        let mut reader = io::BufReader::new(std::fs::File::open(path)?);
+        let image = match extension.as_deref() {
+            Some("hdr") => return read_hdr(&mut reader),
+            Some("png") => read_png(reader)?,
+            Some("jpg") | Some("jpeg") => read_jpeg(reader)?,
+            Some("ppm") | Some("pgm") => read_ppm(&mut reader)?,
+            _ => return Err(invalid_data("unknown image format, expected `.png`, `.jpg`, `.ppm`, `.pgm` or `.hdr`")),
+        };
+        Ok(if srgb { image.srgb_to_linear() } else { image })
+    }
+
+    fn srgb_to_linear(mut self) -> Image {
+        let decode = |c: f32| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+        for pixel in &mut self.pixels {
+            *pixel = Vec3::new(decode(pixel.r()), decode(pixel.g()), decode(pixel.b()));
+        }
+        self
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: ImageFormat) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(std::fs::File::create(path)?);
+        match format {
+            ImageFormat::Ppm => self.write_ppm(&mut writer)?,
+            ImageFormat::PpmBinary => self.write_ppm_binary(&mut writer)?,
+            ImageFormat::Pfm => self.write_pfm(&mut writer)?,
+        }
+        writer.flush()
+    }
+
+    fn write_ppm<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "P3")?;
+        writeln!(writer, "{} {}", self.width, self.height)?;
+        writeln!(writer, "{}", 255)?;
+        for &c in &self.pixels {
+            let [r, g, b] = to_srgb8(c);
+            writeln!(writer, "{} {} {}", r, g, b)?;
+        }
+        Ok(())
+    }
+
+    fn write_ppm_binary<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for &c in &self.pixels {
+            writer.write_all(&to_srgb8(c))?;
+        }
+        Ok(())
+    }
+
+    fn write_pfm<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        // Negative scale marks little-endian data, rows are stored from the bottom.
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.pixels.chunks(self.width).rev() {
+            for c in row {
+                for channel in &c.raw {
+                    writer.write_all(&channel.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Encodes linear values with the sRGB transfer function and quantizes them to 8 bits.
+fn to_srgb8(c: Vec3) -> [u8; 3] {
+    let encode = |v: f32| {
+        let v = v.clamp(0.0, 1.0);
+        if v <= 0.003_130_8 { 12.92 * v } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+    };
+    let quantize = |v: f32| (255.99 * encode(v)) as u8;
+    [quantize(c.r()), quantize(c.g()), quantize(c.b())]
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Image of samples in [0, 1] stored row by row with `channels` per pixel, either gray
+/// or red, green and blue, each optionally followed by alpha.
+fn from_samples(width: usize, height: usize, channels: usize, samples: &[f32]) -> io::Result<Image> {
+    if width.checked_mul(height).and_then(|n| n.checked_mul(channels)) != Some(samples.len()) {
+        return Err(invalid_data("image data does not match its size"));
+    }
+    let pixels = samples.chunks(channels)
+        .map(|c| if channels < 3 { Vec3::new(c[0], c[0], c[0]) } else { Vec3::new(c[0], c[1], c[2]) })
+        .collect();
+    Ok(Image::from_pixels(width, height, pixels))
+}
+
+fn read_png<R: Read>(reader: R) -> io::Result<Image> {
+    let mut decoder = png::Decoder::new(reader);
+    // Palettes and bit depths below eight are expanded to plain 8-bit samples.
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data)?;
+
+    let samples: Vec<f32> = match info.bit_depth {
+        png::BitDepth::Sixteen => data.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]]) as f32 / 65535.0).collect(),
+        _ => data.iter().map(|&c| c as f32 / 255.0).collect(),
+    };
+    from_samples(info.width as usize, info.height as usize, info.color_type.samples(), &samples)
+}
+
+fn read_jpeg<R: Read>(reader: R) -> io::Result<Image> {
+    let mut decoder = jpeg_decoder::Decoder::new(reader);
+    let data = decoder.decode().map_err(|e| invalid_data(&e.to_string()))?;
+    let info = decoder.info().ok_or_else(|| invalid_data("missing JPEG header"))?;
+    let channels = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => 1,
+        jpeg_decoder::PixelFormat::RGB24 => 3,
+        jpeg_decoder::PixelFormat::CMYK32 => return Err(invalid_data("CMYK JPEG images are not supported")),
+    };
+    let samples: Vec<f32> = data.iter().map(|&c| c as f32 / 255.0).collect();
+    from_samples(info.width as usize, info.height as usize, channels, &samples)
+}
+
+/// Largest number of pixels accepted from the header of an image file, so that corrupted
+/// headers are reported as errors instead of exhausting memory.
+const MAX_PIXELS: usize = 1 << 28;
+
+/// Number of pixels of an image of the given size read from a file header.
+fn pixel_count(width: usize, height: usize) -> io::Result<usize> {
+    width.checked_mul(height)
+        .filter(|&count| count <= MAX_PIXELS)
+        .ok_or_else(|| invalid_data("image is too large"))
+}
+
+/// Reads a binary or plain text PPM or PGM image with up to 16 bits per sample.
+fn read_ppm<R: BufRead>(reader: &mut R) -> io::Result<Image> {
+    let (channels, binary) = match read_ppm_token(reader)?.as_str() {
+        "P2" => (1, false),
+        "P3" => (3, false),
+        "P5" => (1, true),
+        "P6" => (3, true),
+        _ => return Err(invalid_data("not a PPM or PGM file")),
+    };
+    let number = |reader: &mut R| -> io::Result<usize> {
+        read_ppm_token(reader)?.parse().map_err(|_| invalid_data("invalid number in PPM file"))
+    };
+    let width = number(reader)?;
+    let height = number(reader)?;
+    let max = number(reader)?;
+    if max == 0 || max > 65535 {
+        return Err(invalid_data("maximum value of PPM samples must be between 1 and 65535"));
+    }
+    let count = channels * pixel_count(width, height)?;
+
+    let (wide, max) = (max > 255, max as f32);
+    let samples: Vec<f32> = if !binary {
+        (0..count).map(|_| number(reader).map(|value| value as f32 / max)).collect::<io::Result<_>>()?
+    } else if !wide {
+        let mut data = vec![0; count];
+        reader.read_exact(&mut data)?;
+        data.iter().map(|&value| value as f32 / max).collect()
+    } else {
+        let mut data = vec![0; 2 * count];
+        reader.read_exact(&mut data)?;
+        data.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]]) as f32 / max).collect()
+    };
+    from_samples(width, height, channels, &samples)
+}
+
+/// Next whitespace separated token of a PPM header, skipping comments. The single whitespace
+/// character after it is consumed too, as binary data starts right after it.
+fn read_ppm_token<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut token = String::new();
+    let mut comment = false;
+    loop {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            if token.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of PPM file"));
+            }
+            return Ok(token);
+        }
+        let c = byte[0] as char;
+        if comment {
+            comment = c != '\n';
+        } else if c == '#' && token.is_empty() {
+            comment = true;
+        } else if c.is_ascii_whitespace() {
+            if !token.is_empty() {
+                return Ok(token);
+            }
+        } else {
+            token.push(c);
+        }
+    }
+}
+
+fn read_hdr<R: BufRead>(reader: &mut R) -> io::Result<Image> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(invalid_data("not a Radiance HDR file"));
+    }
+
+    // Header lines end with an empty one, followed by the resolution.
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid_data("unexpected end of HDR header"));
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(invalid_data("unsupported HDR pixel format"));
+        }
+    }
+
+    line.clear();
+    reader.read_line(&mut line)?;
+    let (height, width) = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["-Y", height, "+X", width] => (height.parse().ok(), width.parse().ok()),
+        _ => return Err(invalid_data("unsupported HDR orientation")),
+    };
+    let (width, height): (usize, usize) = match (width, height) {
+        (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+        _ => return Err(invalid_data("invalid HDR resolution")),
+    };
+
+    pixel_count(width, height)?;
+
+    // Pixels are added as scanlines are decoded, a truncated file claiming a large
+    // resolution fails before all of its memory is allocated.
+    let mut pixels = Vec::new();
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        read_hdr_scanline(reader, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|&rgbe| from_rgbe(rgbe)));
+    }
+    Ok(Image::from_pixels(width, height, pixels))
+}
+
+fn read_hdr_scanline<R: Read>(reader: &mut R, scanline: &mut [[u8; 4]]) -> io::Result<()> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    reader.read_exact(&mut first)?;
+
+    if (8..0x8000).contains(&width) && first[0] == 2 && first[1] == 2 && first[2] & 0x80 == 0 {
+        // Adaptive run-length encoding, each of the four components stored separately.
+        if (usize::from(first[2]) << 8 | usize::from(first[3])) != width {
+            return Err(invalid_data("mismatched HDR scanline length"));
+        }
+        for component in 0..4 {
+            let mut x = 0;
+            while x < width {
+                let mut header = [0u8; 2];
+                reader.read_exact(&mut header[..1])?;
+                let (run, count) = if header[0] > 128 {
+                    reader.read_exact(&mut header[1..])?;
+                    (true, usize::from(header[0] - 128))
+                } else {
+                    (false, usize::from(header[0]))
+                };
+                if count == 0 || x + count > width {
+                    return Err(invalid_data("corrupted HDR scanline"));
+                }
+                for pixel in &mut scanline[x..x + count] {
+                    if !run {
+                        reader.read_exact(&mut header[1..])?;
+                    }
+                    pixel[component] = header[1];
+                }
+                x += count;
+            }
+        }
+        return Ok(());
+    }
+
+    // Flat pixels, possibly with the old run-length encoding repeating the previous pixel.
+    let mut x = 0;
+    let mut shift = 0;
+    let mut pixel = first;
+    loop {
+        if pixel[0] == 1 && pixel[1] == 1 && pixel[2] == 1 {
+            // Consecutive markers multiply the count by 256, which no scanline needs three times.
+            if x == 0 || shift >= 24 {
+                return Err(invalid_data("corrupted HDR scanline"));
+            }
+            let count = usize::from(pixel[3]) << shift;
+            if x + count > width {
+                return Err(invalid_data("corrupted HDR scanline"));
+            }
+            let previous = scanline[x - 1];
+            for p in &mut scanline[x..x + count] {
+                *p = previous;
+            }
+            x += count;
+            shift += 8;
+        } else {
+            scanline[x] = pixel;
+            x += 1;
+            shift = 0;
+        }
+        if x == width {
+            return Ok(());
+        }
+        reader.read_exact(&mut pixel)?;
+    }
+}
+
+fn from_rgbe([r, g, b, e]: [u8; 4]) -> Vec3 {
+    if e == 0 {
+        return Vec3::zeros();
+    }
+    let scale = 2.0f32.powi(i32::from(e) - 136);
+    Vec3::new(f32::from(r) * scale, f32::from(g) * scale, f32::from(b) * scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_run_length_encoded_and_flat_hdr_scanlines() {
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 8\n".to_vec();
+        // New encoding: every component is a single run of eight.
+        data.extend_from_slice(&[2, 2, 0, 8]);
+        for &component in &[128, 64, 32, 129] {
+            data.extend_from_slice(&[128 + 8, component]);
+        }
+        // Flat pixels with the old encoding repeating the first one seven times.
+        data.extend_from_slice(&[64, 64, 64, 130, 1, 1, 1, 7]);
+
+        let image = read_hdr(&mut &data[..]).unwrap();
+        assert_eq!((image.width(), image.height()), (8, 2));
+        for x in 0..8 {
+            assert_eq!(image.pixel(x, 0).raw, [1.0, 0.5, 0.25]);
+            assert_eq!(image.pixel(x, 1).raw, [1.0, 1.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn reads_plain_and_binary_ppm() {
+        let plain = b"P3\n# comment\n2 1\n255\n255 0 0  0 51 255\n";
+        let image = read_ppm(&mut &plain[..]).unwrap();
+        assert_eq!((image.width(), image.height()), (2, 1));
+        assert_eq!(image.pixel(0, 0).raw, [1.0, 0.0, 0.0]);
+        assert_eq!(image.pixel(1, 0).raw, [0.0, 0.2, 1.0]);
+
+        let mut binary = b"P5 1 2 65535\n".to_vec();
+        binary.extend_from_slice(&[0xff, 0xff, 0x00, 0x00]);
+        let image = read_ppm(&mut &binary[..]).unwrap();
+        assert_eq!(image.pixel(0, 0).raw, [1.0, 1.0, 1.0]);
+        assert_eq!(image.pixel(0, 1).raw, [0.0, 0.0, 0.0]);
+    }
+
+    fn encode_png(width: u32, height: u32, color: png::ColorType, depth: png::BitDepth, data: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![];
+        {
+            let mut encoder = png::Encoder::new(&mut encoded, width, height);
+            encoder.set_color(color);
+            encoder.set_depth(depth);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(data).unwrap();
+        }
+        encoded
+    }
+
+    #[test]
+    fn reads_png() {
+        let data = [255, 0, 0, 255, 0, 51, 255, 0];
+        let encoded = encode_png(2, 1, png::ColorType::RGBA, png::BitDepth::Eight, &data);
+        let image = read_png(&encoded[..]).unwrap();
+        assert_eq!((image.width(), image.height()), (2, 1));
+        assert_eq!(image.pixel(0, 0).raw, [1.0, 0.0, 0.0]);
+        assert_eq!(image.pixel(1, 0).raw, [0.0, 0.2, 1.0]);
+
+        let data = [0xff, 0xff, 0x00, 0x00];
+        let encoded = encode_png(1, 2, png::ColorType::Grayscale, png::BitDepth::Sixteen, &data);
+        let image = read_png(&encoded[..]).unwrap();
+        assert_eq!(image.pixel(0, 0).raw, [1.0, 1.0, 1.0]);
+        assert_eq!(image.pixel(0, 1).raw, [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn load_chooses_format_by_extension() {
+        let png = encode_png(1, 1, png::ColorType::RGB, png::BitDepth::Eight, &[255, 128, 0]);
+        let ppm = b"P3 1 1 255 255 128 0\n";
+        let path = |name: &str| std::env::temp_dir().join(format!("mrtx-{}-{}", std::process::id(), name));
+        let load = |name: &str, data: &[u8], srgb: bool| {
+            std::fs::write(path(name), data).unwrap();
+            let image = Image::load(path(name), srgb);
+            std::fs::remove_file(path(name)).unwrap();
+            image
+        };
+
+        let linear = load("linear.PNG", &png, false).unwrap();
+        assert_eq!(linear.pixel(0, 0).raw, [1.0, 128.0 / 255.0, 0.0]);
+        let decoded = load("decoded.ppm", ppm, true).unwrap();
+        assert!((decoded.pixel(0, 0).g() - 0.2158).abs() < 1e-4);
+
+        // Contents are not sniffed, so a PNG named like a PPM file is rejected.
+        assert!(load("mislabeled.ppm", &png, true).is_err());
+        let error = load("image.tga", ppm, true).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decodes_srgb_to_linear() {
+        let image = Image::from_pixels(1, 1, vec![Vec3::new(0.0, 0.5, 1.0)]).srgb_to_linear();
+        let [black, gray, white] = image.pixel(0, 0).raw;
+        assert_eq!((black, white), (0.0, 1.0));
+        assert!((gray - 0.2140).abs() < 1e-4);
+    }
+
+    #[test]
+    fn encodes_linear_to_srgb() {
+        assert_eq!(to_srgb8(Vec3::new(0.0, 1.0, 2.0)), [0, 255, 255]);
+        assert_eq!(to_srgb8(Vec3::new(-1.0, 0.001, 0.5)), [0, 3, 188]);
+        // Encoding inverts decoding up to quantization.
+        let original = [0.2, 0.4, 0.8];
+        let decoded = Image::from_pixels(1, 1, vec![Vec3 { raw: original }]).srgb_to_linear();
+        for (encoded, original) in to_srgb8(decoded.pixel(0, 0)).iter().zip(&original) {
+            assert!((*encoded as f32 / 255.0 - original).abs() < 1.0 / 255.0, "{} {}", encoded, original);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_hdr() {
+        let data = b"#?RADIANCE\n\n-Y 2 +X 8\n\x02\x02\x00\x08\x88";
+        let error = read_hdr(&mut &data[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A single flat scanline of an image at the size limit.
+        let mut data = b"#?RADIANCE\n\n-Y 16384 +X 16384\n".to_vec();
+        data.resize(data.len() + 4 * 16384, 128);
+        let error = read_hdr(&mut &data[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_huge_hdr_resolution_and_runs() {
+        let data = b"#?RADIANCE\n\n-Y 100000 +X 100000\n";
+        let error = read_hdr(&mut &data[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let data = format!("#?RADIANCE\n\n-Y {} +X {}\n", usize::MAX, usize::MAX);
+        let error = read_hdr(&mut data.as_bytes()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Old run-length markers repeated until the count would overflow.
+        let mut data = b"#?RADIANCE\n\n-Y 1 +X 4\n".to_vec();
+        data.extend_from_slice(&[64, 64, 64, 130]);
+        for _ in 0..10 {
+            data.extend_from_slice(&[1, 1, 1, 0]);
+        }
+        let error = read_hdr(&mut &data[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::hitable::{Hitable, HitRecord};
+use crate::ray::Ray;
+use crate::geometry::Vec3;
+use crate::aabb::Aabb;
+
+/// Moves another object, such as a mesh, by `offset0` at `time0` and `offset1` at `time1`,
+/// interpolating linearly in between. Outside of that interval the object stays in place.
+pub struct MovingInstance {
+    object: Box<dyn Hitable>,
+    offset0: Vec3,
+    offset1: Vec3,
+    time0: f32,
+    time1: f32,
+}
+
+impl MovingInstance {
+    pub fn new(object: Box<dyn Hitable>, offset0: Vec3, offset1: Vec3, time0: f32, time1: f32) -> Self {
+        Self {
+            object,
+            offset0,
+            offset1,
+            time0,
+            time1,
+        }
+    }
+
+    pub fn offset(&self, time: f32) -> Vec3 {
+        if self.time1 <= self.time0 {
+            return self.offset0;
+        }
+        let s = ((time - self.time0) / (self.time1 - self.time0)).clamp(0.0, 1.0);
+        self.offset0 + s * (self.offset1 - self.offset0)
+    }
+
+    /// The ray in the space of the object, which is not moved at all.
+    fn local_ray(&self, ray: &Ray) -> (Ray, Vec3) {
+        let offset = self.offset(ray.time);
+        (Ray { origin: ray.origin - offset, ..*ray }, offset)
+    }
+}
+
+impl Hitable for MovingInstance {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let (local, offset) = self.local_ray(ray);
+        let record = self.object.hit(&local, t_min, t_max)?;
+        Some(HitRecord { point: record.point + offset, ..record })
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        let (local, _) = self.local_ray(ray);
+        self.object.hit_any(&local, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let aabb = self.object.bounding_box();
+        Aabb::union(aabb.transformed(1.0, self.offset0), aabb.transformed(1.0, self.offset1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sphere::Sphere;
+    use crate::material::Lambertian;
+    use crate::texture::ConstantTexture;
+    use std::sync::Arc;
+
+    #[test]
+    fn object_is_hit_where_it_is_at_ray_time() {
+        let sphere = Sphere {
+            center: Vec3::zeros(),
+            radius: 0.5,
+            material: Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.5)) }),
+        };
+        let moving = MovingInstance::new(Box::new(sphere), Vec3::zeros(), Vec3::new(2.0, 0.0, 0.0), 0.0, 1.0);
+
+        let ray_at = |x: f32, time: f32| Ray::at_time(Vec3::new(x, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), time);
+        assert!(moving.hit(&ray_at(0.0, 0.0), 0.0, f32::INFINITY).is_some());
+        assert!(moving.hit(&ray_at(0.0, 1.0), 0.0, f32::INFINITY).is_none());
+        assert!(moving.hit_any(&ray_at(2.0, 1.0), 0.0, f32::INFINITY));
+
+        let record = moving.hit(&ray_at(1.0, 0.5), 0.0, f32::INFINITY).unwrap();
+        assert!((record.point - Vec3::new(1.0, 0.0, 0.5)).length() < 1e-5);
+        assert!((record.t - 4.5).abs() < 1e-5);
+
+        let aabb = moving.bounding_box();
+        assert_eq!(aabb.min().x(), -0.5);
+        assert_eq!(aabb.max().x(), 2.5);
+    }
+}
@@ -0,0 +1,51 @@
+//! Path tracing renderer.
+//!
+//! Scenes are either loaded from TOML descriptions with [`Scene::load`] or assembled in code
+//! from [`Hitable`] objects, and then turned into an [`Image`] with [`render`].
+
+pub mod geometry;
+pub mod ray;
+pub mod hitable;
+pub mod hitable_list;
+pub mod sphere;
+pub mod instance;
+pub mod light;
+pub mod environment;
+pub mod camera;
+pub mod stereo;
+pub mod material;
+pub mod texture;
+pub mod microfacet;
+pub mod principled;
+pub mod triangulated_model;
+pub mod mesh;
+pub mod mesh_utils;
+pub mod aabb;
+pub mod bvh;
+pub mod scene;
+pub mod image;
+mod mesh_bvh;
+mod rng;
+mod renderer;
+
+pub use crate::geometry::Vec3;
+pub use crate::ray::{Ray, RayCone};
+pub use crate::hitable::{Hitable, HitRecord};
+pub use crate::hitable_list::HitableList;
+pub use crate::bvh::BvhNode;
+pub use crate::sphere::{Sphere, MovingSphere};
+pub use crate::instance::MovingInstance;
+pub use crate::light::{Light, LightSample, SphereLight, MeshLight, PointLight, SpotLight, DirectionalLight, EnvironmentLight};
+pub use crate::environment::{Environment, ConstantEnvironment, GradientEnvironment, ImageEnvironment, SunSkyEnvironment};
+pub use crate::camera::{Camera, PerspectiveCamera, OrthographicCamera, FisheyeCamera, EquirectangularCamera, ShutterCamera};
+pub use crate::stereo::{StereoCamera, StereoLayout};
+pub use crate::material::{Material, Scattered, Lambertian, Metal, Dielectric, DiffuseLight};
+pub use crate::texture::{Texture, ConstantTexture, CheckerTexture, GradientTexture, ImageTexture, TextureFilter, WrapMode};
+pub use crate::microfacet::{RoughConductor, RoughDielectric};
+pub use crate::principled::Principled;
+pub use crate::mesh::{Mesh, MeshBuilder};
+pub use crate::mesh_utils::load_obj;
+pub use crate::triangulated_model::TriangulatedModel;
+pub use crate::scene::{Scene, RenderSettings, SceneError};
+pub use crate::image::{Image, ImageFormat};
+pub use crate::renderer::render;
@@ -0,0 +1,535 @@
+use crate::geometry::Vec3;
+use crate::hitable::HitRecord;
+use crate::material::Material;
+use crate::mesh::Mesh;
+use crate::ray::Ray;
+use crate::sphere::{Sphere, MovingSphere, sphere_uv};
+use crate::environment::Environment;
+use crate::hitable::Hitable;
+use crate::triangulated_model::{ray_triangle_intersect, TriangleHit};
+use crate::rng::{self, random_unit_vector};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Direction in which some point is illuminated by a light.
+pub struct LightSample {
+    /// Unit vector pointing from the illuminated point towards the light.
+    pub direction: Vec3,
+    /// Distance to the light, infinite for directional lights.
+    pub distance: f32,
+    /// Radiance arriving from the light, or irradiance for delta lights.
+    pub radiance: Vec3,
+    /// Probability density of choosing `direction`, with respect to solid angle at the illuminated
+    /// point. May be infinite when an area light is seen exactly edge-on, and is one for delta lights.
+    pub pdf: f32,
+}
+
+impl LightSample {
+    fn from_point(from: Vec3, point: Vec3, radiance: Vec3, pdf: f32) -> Self {
+        let to_light = point - from;
+        let distance = to_light.length();
+        Self { direction: to_light / distance, distance, radiance, pdf }
+    }
+
+    fn none() -> Self {
+        Self { direction: Vec3::new(0.0, 1.0, 0.0), distance: 0.0, radiance: Vec3::zeros(), pdf: 0.0 }
+    }
+}
+
+/// Light that can be sampled directly, so that it is found by shadow rays instead of relying
+/// on scattered rays hitting it by chance.
+pub trait Light: Send + Sync {
+    fn sample(&self, point: Vec3, time: f32) -> LightSample;
+
+    /// Probability density with respect to solid angle of `sample` choosing `direction`
+    /// when illuminating `point`, zero when the light is not in that direction.
+    fn pdf(&self, point: Vec3, direction: Vec3, time: f32) -> f32;
+
+    /// Parameter `t` of the nearest point of the light hit by the ray beyond `t_min`, which tells
+    /// which light a scattered ray found. `None` when the ray misses it and for lights without area
+    /// or infinitely far away, which rays cannot hit.
+    fn intersect(&self, ray: &Ray, t_min: f32) -> Option<f32>;
+
+    /// Whether the light has no area, like a point light. Such lights cannot be hit by rays,
+    /// and all of them are evaluated at every bounce instead of one chosen at random.
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    /// Whether the light surrounds the scene infinitely far away, so that it is reached
+    /// only by rays that miss the world.
+    fn is_infinite(&self) -> bool {
+        false
+    }
+}
+
+/// Evaluates emission of `material` at a sampled point as seen from `from`.
+fn emitted(material: &Arc<dyn Material>, from: Vec3, point: Vec3, normal: Vec3, (u, v): (f32, f32), time: f32) -> Vec3 {
+    let ray = Ray::at_time(from, point - from, time);
+    let record = HitRecord { t: 1.0, point, normal, u, v, footprint: 0.0, material: material.clone() };
+    material.emitted(&ray, &record)
+}
+
+/// Converts density with respect to area on a light into density with respect to solid angle.
+fn area_to_solid_angle(pdf_area: f32, from: Vec3, point: Vec3, normal: Vec3) -> f32 {
+    let to_light = point - from;
+    let distance_squared = to_light.squared_len();
+    let cosine = Vec3::dot(normal, to_light).abs() / distance_squared.sqrt();
+    pdf_area * distance_squared / cosine
+}
+
+/// Spherical light, optionally moving like `MovingSphere`.
+pub struct SphereLight {
+    sphere: MovingSphere,
+}
+
+impl SphereLight {
+    pub fn new(sphere: &Sphere) -> Self {
+        Self::moving(&MovingSphere {
+            center0: sphere.center,
+            center1: sphere.center,
+            time0: 0.0,
+            time1: 0.0,
+            radius: sphere.radius,
+            material: sphere.material.clone(),
+        })
+    }
+
+    pub fn moving(sphere: &MovingSphere) -> Self {
+        Self {
+            sphere: MovingSphere { material: sphere.material.clone(), ..*sphere },
+        }
+    }
+}
+
+impl Light for SphereLight {
+    fn sample(&self, point: Vec3, time: f32) -> LightSample {
+        let center = self.sphere.center(time);
+        let radius = self.sphere.radius.abs();
+        let material = &self.sphere.material;
+        let to_center = center - point;
+        let distance_squared = to_center.squared_len();
+
+        if distance_squared <= radius * radius {
+            // Inside of the sphere all of it is visible, so sample its whole area.
+            let normal = random_unit_vector();
+            let on_light = center + radius * normal;
+            return LightSample::from_point(
+                point,
+                on_light,
+                emitted(material, point, on_light, normal, sphere_uv(normal), time),
+                area_to_solid_angle(1.0 / (4.0 * PI * radius * radius), point, on_light, normal),
+            );
+        }
+
+        // Outside of the sphere sample uniformly the cone of directions in which it is visible.
+        let distance = distance_squared.sqrt();
+        let axis = to_center / distance;
+        let sin_squared_max = radius * radius / distance_squared;
+        let cos_max = (1.0 - sin_squared_max).max(0.0).sqrt();
+        // Same as 1 - cos_max, without cancellation for small and distant spheres.
+        let solid_angle_fraction = sin_squared_max / (1.0 + cos_max);
+
+        let cos_theta = 1.0 - rng::random::<f32>() * solid_angle_fraction;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng::random::<f32>();
+        let (tangent, bitangent) = axis.orthonormal_basis();
+        let direction = sin_theta * (phi.cos() * tangent + phi.sin() * bitangent) + cos_theta * axis;
+
+        // Distance to the nearer intersection of the sampled direction with the sphere.
+        let half_chord = (radius * radius - distance_squared * sin_theta * sin_theta).max(0.0).sqrt();
+        let on_light = point + (distance * cos_theta - half_chord) * direction;
+        let normal = (on_light - center).normalize();
+
+        LightSample::from_point(
+            point,
+            on_light,
+            emitted(material, point, on_light, normal, sphere_uv(normal), time),
+            1.0 / (2.0 * PI * solid_angle_fraction),
+        )
+    }
+
+    fn pdf(&self, point: Vec3, direction: Vec3, time: f32) -> f32 {
+        let center = self.sphere.center(time);
+        let radius = self.sphere.radius.abs();
+        let ray = Ray::at_time(point, direction, time);
+        let on_light = match self.intersect(&ray, 0.0) {
+            Some(t) => ray.point_at_parameter(t),
+            None => return 0.0,
+        };
+
+        let distance_squared = (center - point).squared_len();
+        if distance_squared <= radius * radius {
+            let normal = (on_light - center).normalize();
+            return area_to_solid_angle(1.0 / (4.0 * PI * radius * radius), point, on_light, normal);
+        }
+        let sin_squared_max = radius * radius / distance_squared;
+        let cos_max = (1.0 - sin_squared_max).max(0.0).sqrt();
+        1.0 / (2.0 * PI * sin_squared_max / (1.0 + cos_max))
+    }
+
+    fn intersect(&self, ray: &Ray, t_min: f32) -> Option<f32> {
+        self.sphere.hit(ray, t_min, f32::INFINITY).map(|record| record.t)
+    }
+}
+
+/// Light made of triangles of a mesh, sampled in proportion to their area.
+pub struct MeshLight {
+    mesh: Arc<Mesh>,
+    /// Running sum of areas of triangles, in the order of triangles of the mesh.
+    cumulative_area: Vec<f32>,
+    material: Arc<dyn Material>,
+}
+
+impl MeshLight {
+    pub fn new(mesh: Arc<Mesh>, material: Arc<dyn Material>) -> Self {
+        let mut total = 0.0;
+        let cumulative_area = mesh.iter_triangles()
+            .map(|(v0, v1, v2)| {
+                total += 0.5 * Vec3::cross(v1.position - v0.position, v2.position - v0.position).length();
+                total
+            })
+            .collect();
+
+        Self {
+            mesh,
+            cumulative_area,
+            material,
+        }
+    }
+
+    fn total_area(&self) -> f32 {
+        self.cumulative_area.last().copied().unwrap_or(0.0)
+    }
+
+    fn geometric_normal(&self, i: usize) -> Vec3 {
+        let (v0, v1, v2) = self.mesh.triangle(i);
+        Vec3::cross(v1.position - v0.position, v2.position - v0.position).normalize()
+    }
+
+    /// Index of the triangle nearest along the ray beyond `t_min` and its parameter `t`.
+    fn closest_triangle(&self, ray: &Ray, t_min: f32) -> Option<(usize, f32)> {
+        let mut closest = None;
+        self.mesh.bvh().traverse(ray, t_min, f32::INFINITY, |i, closest_so_far| {
+            let (v0, v1, v2) = self.mesh.triangle(i);
+            let hit = ray_triangle_intersect(ray, v0.position, v1.position, v2.position)?;
+            if !(hit.t > t_min && hit.t < closest_so_far) {
+                return None;
+            }
+            closest = Some((i, hit.t));
+            Some(hit.t)
+        });
+        closest
+    }
+}
+
+impl Light for MeshLight {
+    fn sample(&self, point: Vec3, time: f32) -> LightSample {
+        let total_area = self.total_area();
+        if total_area <= 0.0 {
+            return LightSample::none();
+        }
+
+        // Triangles without area are never chosen, as their running sum equals that of the previous one.
+        let target = rng::random::<f32>() * total_area;
+        let index = self.cumulative_area.partition_point(|&area| area <= target)
+            .min(self.cumulative_area.len() - 1);
+        let (v0, v1, v2) = self.mesh.triangle(index);
+
+        let su = rng::random::<f32>().sqrt();
+        let b1 = rng::random::<f32>() * su;
+        let on_light = (1.0 - su) * v0.position + (su - b1) * v1.position + b1 * v2.position;
+        let normal = self.geometric_normal(index);
+        let hit = TriangleHit { t: 0.0, barycentric: [1.0 - su, su - b1, b1] };
+        let uv = hit.interpolate_uv(v0.uv, v1.uv, v2.uv);
+
+        LightSample::from_point(
+            point,
+            on_light,
+            emitted(&self.material, point, on_light, normal, uv, time),
+            area_to_solid_angle(1.0 / total_area, point, on_light, normal),
+        )
+    }
+
+    fn pdf(&self, point: Vec3, direction: Vec3, _time: f32) -> f32 {
+        let total_area = self.total_area();
+        if total_area <= 0.0 {
+            return 0.0;
+        }
+
+        let ray = Ray::new(point, direction);
+        match self.closest_triangle(&ray, 0.0) {
+            Some((i, t)) => area_to_solid_angle(1.0 / total_area, point, ray.point_at_parameter(t), self.geometric_normal(i)),
+            None => 0.0,
+        }
+    }
+
+    fn intersect(&self, ray: &Ray, t_min: f32) -> Option<f32> {
+        self.closest_triangle(ray, t_min).map(|(_, t)| t)
+    }
+}
+
+/// Light emitted from a single point equally in all directions, with `intensity` being
+/// the irradiance it produces at a unit distance, falling off with the squared distance.
+pub struct PointLight {
+    pub position: Vec3,
+    pub intensity: Vec3,
+}
+
+impl Light for PointLight {
+    fn sample(&self, point: Vec3, _time: f32) -> LightSample {
+        let distance_squared = (self.position - point).squared_len();
+        LightSample::from_point(point, self.position, self.intensity / distance_squared, 1.0)
+    }
+
+    fn pdf(&self, _point: Vec3, _direction: Vec3, _time: f32) -> f32 {
+        0.0
+    }
+
+    fn intersect(&self, _ray: &Ray, _t_min: f32) -> Option<f32> {
+        None
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+/// Point light shining only into a cone around `direction`. Its intensity is full within
+/// the inner angle and smoothly falls off to zero at the outer angle.
+pub struct SpotLight {
+    position: Vec3,
+    direction: Vec3,
+    intensity: Vec3,
+    cos_inner: f32,
+    cos_outer: f32,
+}
+
+impl SpotLight {
+    /// Angles are measured in degrees from `direction` to the edge of the cone.
+    pub fn new(position: Vec3, direction: Vec3, intensity: Vec3, inner_angle: f32, outer_angle: f32) -> Self {
+        let outer_angle = outer_angle.max(inner_angle);
+        Self {
+            position,
+            direction: direction.normalize(),
+            intensity,
+            cos_inner: inner_angle.to_radians().cos(),
+            cos_outer: outer_angle.to_radians().cos(),
+        }
+    }
+
+    fn falloff(&self, cosine: f32) -> f32 {
+        if cosine >= self.cos_inner {
+            return 1.0;
+        }
+        if cosine <= self.cos_outer {
+            return 0.0;
+        }
+        let s = (cosine - self.cos_outer) / (self.cos_inner - self.cos_outer);
+        s * s * (3.0 - 2.0 * s)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, point: Vec3, _time: f32) -> LightSample {
+        let distance_squared = (self.position - point).squared_len();
+        let mut sample = LightSample::from_point(point, self.position, self.intensity / distance_squared, 1.0);
+        sample.radiance = sample.radiance * self.falloff(-Vec3::dot(sample.direction, self.direction));
+        sample
+    }
+
+    fn pdf(&self, _point: Vec3, _direction: Vec3, _time: f32) -> f32 {
+        0.0
+    }
+
+    fn intersect(&self, _ray: &Ray, _t_min: f32) -> Option<f32> {
+        None
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+/// Light coming from infinitely far away along `direction`, like sunlight,
+/// with `irradiance` measured on a surface perpendicular to it.
+pub struct DirectionalLight {
+    direction: Vec3,
+    irradiance: Vec3,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3, irradiance: Vec3) -> Self {
+        Self {
+            direction: direction.normalize(),
+            irradiance,
+        }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _point: Vec3, _time: f32) -> LightSample {
+        LightSample {
+            direction: -self.direction,
+            distance: f32::INFINITY,
+            radiance: self.irradiance,
+            pdf: 1.0,
+        }
+    }
+
+    fn pdf(&self, _point: Vec3, _direction: Vec3, _time: f32) -> f32 {
+        0.0
+    }
+
+    fn intersect(&self, _ray: &Ray, _t_min: f32) -> Option<f32> {
+        None
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+/// Environment surrounding the scene, sampled as the environment chooses.
+pub struct EnvironmentLight {
+    environment: Arc<dyn Environment>,
+}
+
+impl EnvironmentLight {
+    pub fn new(environment: Arc<dyn Environment>) -> Self {
+        Self { environment }
+    }
+}
+
+impl Light for EnvironmentLight {
+    fn sample(&self, _point: Vec3, _time: f32) -> LightSample {
+        let (direction, pdf) = self.environment.sample();
+        LightSample {
+            direction,
+            distance: f32::INFINITY,
+            radiance: self.environment.radiance(direction),
+            pdf,
+        }
+    }
+
+    fn pdf(&self, _point: Vec3, direction: Vec3, _time: f32) -> f32 {
+        self.environment.pdf(direction)
+    }
+
+    fn intersect(&self, _ray: &Ray, _t_min: f32) -> Option<f32> {
+        None
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::DiffuseLight;
+    use crate::texture::ConstantTexture;
+    use crate::mesh::MeshBuilder;
+
+    fn lamp() -> Arc<dyn Material> {
+        Arc::new(DiffuseLight { emit: Arc::new(ConstantTexture::new(Vec3::new(1.0, 2.0, 3.0))) })
+    }
+
+    #[test]
+    fn sphere_samples_cover_visible_cap() {
+        let light = SphereLight::new(&Sphere { center: Vec3::new(0.0, 0.0, -4.0), radius: 1.0, material: lamp() });
+        let point = Vec3::zeros();
+
+        let count = 10_000;
+        let mut solid_angle = 0.0;
+        for _ in 0..count {
+            let sample = light.sample(point, 0.0);
+            let on_light = point + sample.distance * sample.direction;
+            assert!(((on_light - Vec3::new(0.0, 0.0, -4.0)).length() - 1.0).abs() < 1e-4);
+            assert!(Vec3::dot(on_light - Vec3::new(0.0, 0.0, -4.0), sample.direction) < 0.0);
+            assert_eq!(sample.radiance.z(), 3.0);
+            solid_angle += 1.0 / sample.pdf / count as f32;
+        }
+
+        let expected = 2.0 * PI * (1.0 - (15.0f32 / 16.0).sqrt());
+        assert!((solid_angle - expected).abs() < 1e-4, "{} {}", solid_angle, expected);
+    }
+
+    #[test]
+    fn mesh_samples_are_on_triangles() {
+        let mut builder = MeshBuilder::new();
+        let v0 = builder.push_vertex(Vec3::new(-1.0, 2.0, -1.0));
+        let v1 = builder.push_vertex(Vec3::new(1.0, 2.0, -1.0));
+        let v2 = builder.push_vertex(Vec3::new(1.0, 2.0, 1.0));
+        let v3 = builder.push_vertex(Vec3::new(-1.0, 2.0, 1.0));
+        builder.push_triangle(v0, v1, v2);
+        builder.push_triangle(v0, v2, v3);
+        let light = MeshLight::new(Arc::new(builder.build()), lamp());
+
+        let point = Vec3::zeros();
+        let (mut left, count) = (0, 10_000);
+        for _ in 0..count {
+            let sample = light.sample(point, 0.0);
+            let on_light = point + sample.distance * sample.direction;
+            assert!((on_light.y() - 2.0).abs() < 1e-5);
+            assert!(on_light.x().abs() <= 1.0 + 1e-5 && on_light.z().abs() <= 1.0 + 1e-5);
+
+            // Density with respect to area is uniform over the 2x2 square.
+            let to_light = on_light - point;
+            let cosine = to_light.y() / to_light.length();
+            let pdf_area = sample.pdf * cosine / to_light.squared_len();
+            assert!((pdf_area - 0.25).abs() < 1e-4);
+            if on_light.x() < 0.0 {
+                left += 1;
+            }
+        }
+        assert!((left as f32 / count as f32 - 0.5).abs() < 0.03);
+    }
+
+    #[test]
+    fn pdf_matches_density_of_samples() {
+        let sphere = SphereLight::new(&Sphere { center: Vec3::new(1.0, 0.5, -3.0), radius: 0.7, material: lamp() });
+        let mut builder = MeshBuilder::new();
+        let v0 = builder.push_vertex(Vec3::new(-1.0, 2.0, -1.0));
+        let v1 = builder.push_vertex(Vec3::new(1.0, 2.5, -1.0));
+        let v2 = builder.push_vertex(Vec3::new(0.0, 2.0, 1.0));
+        builder.push_triangle(v0, v1, v2);
+        let mesh = MeshLight::new(Arc::new(builder.build()), lamp());
+
+        for &point in &[Vec3::zeros(), Vec3::new(1.2, 0.4, -3.1)] {
+            for light in &[&sphere as &dyn Light, &mesh] {
+                for _ in 0..100 {
+                    let sample = light.sample(point, 0.0);
+                    let pdf = light.pdf(point, sample.direction, 0.0);
+                    assert!((pdf - sample.pdf).abs() <= 1e-3 * sample.pdf, "{} {}", pdf, sample.pdf);
+                }
+            }
+        }
+        assert_eq!(sphere.pdf(Vec3::zeros(), Vec3::new(0.0, 0.0, 1.0), 0.0), 0.0);
+        assert_eq!(mesh.pdf(Vec3::zeros(), Vec3::new(0.0, -1.0, 0.0), 0.0), 0.0);
+    }
+
+    #[test]
+    fn delta_lights_fall_off_with_distance_and_angle() {
+        let point = PointLight { position: Vec3::new(0.0, 2.0, 0.0), intensity: Vec3::new(4.0, 4.0, 4.0) };
+        let sample = point.sample(Vec3::zeros(), 0.0);
+        assert_eq!(sample.distance, 2.0);
+        assert_eq!(sample.direction.y(), 1.0);
+        assert_eq!(sample.radiance.x(), 1.0);
+        assert!(point.is_delta());
+
+        let spot = SpotLight::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 1.0), 30.0, 60.0);
+        let at = |x: f32| spot.sample(Vec3::new(x, 0.0, 0.0), 0.0).radiance.x() * (1.0 + x * x);
+        assert_eq!(at(0.0), 1.0);
+        assert!((at(0.5) - 1.0).abs() < 1e-6);
+        assert!(at(1.0) > 0.0 && at(1.0) < 1.0);
+        assert_eq!(at(2.0), 0.0);
+
+        let sun = DirectionalLight::new(Vec3::new(0.0, -2.0, 0.0), Vec3::new(3.0, 3.0, 3.0));
+        let sample = sun.sample(Vec3::new(5.0, 0.0, 5.0), 0.0);
+        assert_eq!(sample.direction.y(), 1.0);
+        assert!(sample.distance.is_infinite());
+        assert_eq!(sample.radiance.x(), 3.0);
+    }
+}
@@ -1,131 +1,161 @@
-use std::io::Write;
 use std::error::Error;
-use rayon::prelude::*;
+use clap::{App, Arg, ArgMatches, crate_version};
+use mrtx::{render, Scene, RenderSettings, ImageFormat};
 
-mod geometry;
-mod ray;
-mod hitable;
-mod hitable_list;
-mod sphere;
-mod camera;
-mod material;
-mod triangulated_model;
-mod mesh;
-mod mesh_utils;
+fn parse_positive<T: std::str::FromStr + PartialOrd + Default>(value: &str) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(v) if v > T::default() => Ok(v),
+        _ => Err(format!("expected a positive integer, got `{}`", value)),
+    }
+}
 
-use crate::geometry::Vec3;
-use crate::ray::Ray;
-use crate::hitable::Hitable;
-use crate::hitable_list::HitableList;
-use crate::sphere::Sphere;
-use crate::camera::Camera;
-use crate::material::{Scattered, Lambertian, Metal, Dielectric};
-use std::sync::Arc;
-use crate::triangulated_model::TriangulatedModel;
-use crate::mesh_utils::load_obj;
+fn cli() -> App<'static, 'static> {
+    App::new("mrtx")
+        .version(crate_version!())
+        .about("Renders a scene description with a path tracer")
+        .arg(Arg::with_name("scene")
+            .help("Scene description file")
+            .default_value("scene.toml")
+            .index(1))
+        .arg(Arg::with_name("width")
+            .long("width")
+            .value_name("PIXELS")
+            .help("Overrides width of the image")
+            .validator(|v| parse_positive::<usize>(&v).map(|_| ())))
+        .arg(Arg::with_name("height")
+            .long("height")
+            .value_name("PIXELS")
+            .help("Overrides height of the image")
+            .validator(|v| parse_positive::<usize>(&v).map(|_| ())))
+        .arg(Arg::with_name("samples")
+            .long("spp")
+            .value_name("COUNT")
+            .help("Overrides number of samples per pixel")
+            .validator(|v| parse_positive::<usize>(&v).map(|_| ())))
+        .arg(Arg::with_name("max-depth")
+            .long("max-depth")
+            .value_name("BOUNCES")
+            .help("Overrides maximum number of bounces of a path")
+            .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|_| format!("expected an integer, got `{}`", v))))
+        .arg(Arg::with_name("min-depth")
+            .long("min-depth")
+            .value_name("BOUNCES")
+            .help("Overrides number of bounces after which paths may be terminated early")
+            .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|_| format!("expected an integer, got `{}`", v))))
+        .arg(Arg::with_name("threads")
+            .long("threads")
+            .short("j")
+            .value_name("COUNT")
+            .help("Number of worker threads [default: number of CPUs]")
+            .validator(|v| parse_positive::<usize>(&v).map(|_| ())))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .value_name("SEED")
+            .help("Seed of the random number generator, makes renders reproducible")
+            .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|_| format!("expected an integer, got `{}`", v))))
+        .arg(Arg::with_name("output")
+            .long("output")
+            .short("o")
+            .value_name("FILE")
+            .help("Overrides output file"))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .help("Output format [default: deduced from output file extension, ppm otherwise]")
+            .possible_values(&["ppm", "ppm-binary", "pfm"]))
+}
 
-fn color(ray: &Ray, hitable: &dyn Hitable, depth: usize) -> Vec3 {
-    match hitable.hit(ray, 0.001, std::f32::INFINITY) {
-        Some(record) => {
-            if depth < 50 {
-                if let Some(Scattered { attenuation, ref scattered }) = record.material.scatter(ray, &record) {
-                    return attenuation * color(scattered, hitable, depth + 1);
-                }
-            }
-            Vec3::new(0.0, 0.0, 0.0)
-        }
-        None => {
-            let uv = ray.direction.normalize();
-            let t = 0.5 * (uv.y() + 1.0);
-            (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
-        }
+fn main() {
+    let matches = cli().get_matches();
+    if let Err(e) = run(&matches) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
     }
 }
 
-fn random_in_unit_sphere() -> Vec3 {
-    loop {
-        let p = 2.0 * Vec3::new(rand::random(), rand::random(), rand::random()) - Vec3::new(1.0, 1.0, 1.0);
-        if p.squared_len() >= 1.0 {
-            return p;
-        }
+fn run(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (scene, mut settings) = Scene::load(matches.value_of("scene").unwrap())?;
+    override_settings(matches, &mut settings)?;
+
+    let format = match matches.value_of("format") {
+        Some(name) => ImageFormat::from_name(name).unwrap(),
+        None => ImageFormat::from_path(&settings.output).unwrap_or(ImageFormat::Ppm),
+    };
+
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(threads) = matches.value_of("threads") {
+        pool = pool.num_threads(threads.parse()?);
     }
-}
+    let pool = pool.build()?;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let width = 1920;
-    let height = 1080;
-    let ns = 100;
+    let image = pool.install(|| render(&scene, &settings));
+    image.save(&settings.output, format)?;
 
-    let hitables = HitableList::from_vec(vec![
-        Box::new(TriangulatedModel::new(
-            load_obj(r"C:\Projects\mrtx\sample.obj"),
-            Arc::new(
-                Metal { albedo: Vec3::new(0.8, 0.6, 0.2) }
-            ),
-        )),
-        Box::new(Sphere {
-            center: Vec3::new(0.0, -100.5, -1.0),
-            radius: 100.0,
-            material: Arc::new(
-                Lambertian { albedo: Vec3::new(0.8, 0.3, 0.0) }
-            ),
-        }),
-        Box::new(Sphere {
-            center: Vec3::new(1.0, 0.0, -1.0),
-            radius: 0.5,
-            material: Arc::new(
-                Metal { albedo: Vec3::new(0.8, 0.6, 0.2) }
-            ),
-        }),
-        Box::new(Sphere {
-            center: Vec3::new(-1.0, 0.0, -1.0),
-            radius: 0.5,
-            material: Arc::new(
-                Dielectric { ref_idx: 1.5 }
-            ),
-        }),
-        Box::new(Sphere {
-            center: Vec3::new(-1.0, 0.0, -1.0),
-            radius: -0.45,
-            material: Arc::new(
-                Dielectric { ref_idx: 1.5 }
-            ),
-        }),
-    ]);
+    Ok(())
+}
 
-    let camera = Camera::default();
+/// Replaces render settings of the scene with those given on the command line.
+fn override_settings(matches: &ArgMatches, settings: &mut RenderSettings) -> Result<(), Box<dyn Error>> {
+    // Values were already validated by the parser.
+    if let Some(width) = matches.value_of("width") {
+        settings.width = width.parse()?;
+    }
+    if let Some(height) = matches.value_of("height") {
+        settings.height = height.parse()?;
+    }
+    if let Some(samples) = matches.value_of("samples") {
+        settings.samples = samples.parse()?;
+    }
+    if let Some(max_depth) = matches.value_of("max-depth") {
+        settings.max_depth = max_depth.parse()?;
+    }
+    if let Some(min_depth) = matches.value_of("min-depth") {
+        settings.min_depth = min_depth.parse()?;
+    }
+    if settings.min_depth > settings.max_depth {
+        return Err("min-depth must not be greater than max-depth".into());
+    }
+    if let Some(seed) = matches.value_of("seed") {
+        settings.seed = Some(seed.parse()?);
+    }
+    if let Some(output) = matches.value_of("output") {
+        settings.output = output.into();
+    }
+    Ok(())
+}
 
-    let mut frame_buffer = vec![Vec3::new(0.0, 0.0, 0.0); width * height];
-    frame_buffer.par_iter_mut().enumerate().for_each(|(n, pixel)| {
-        let i = n % width;
-        let j = height - n / width;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let mut col = Vec3::new(0.0, 0.0, 0.0);
-        for _ in 0..ns {
-            let u = (i as f32 + rand::random::<f32>()) / width as f32;
-            let v = (j as f32 + rand::random::<f32>()) / height as f32;
-            let r = camera.ray(u, v);
-            col = col + color(&r, &hitables, 0);
-        }
-        let c = col / ns as f32;
-        *pixel = Vec3::new(
-            c.x().sqrt(),
-            c.y().sqrt(),
-            c.z().sqrt(),
-        );
-    });
+    fn overridden(args: &[&str]) -> Result<RenderSettings, Box<dyn Error>> {
+        let matches = cli().get_matches_from_safe(std::iter::once("mrtx").chain(args.iter().copied()))?;
+        let mut settings = RenderSettings::default();
+        override_settings(&matches, &mut settings)?;
+        Ok(settings)
+    }
 
-    let mut writer = std::io::BufWriter::new(std::fs::File::create("image.ppm")?);
-    write!(writer, "P3\n")?;
-    write!(writer, "{} {}\n", width, height)?;
-    write!(writer, "{}\n", 255)?;
-    for Vec3 { raw: [r, g, b] } in frame_buffer {
-        let r = (255.99 * r) as i32;
-        let g = (255.99 * g) as i32;
-        let b = (255.99 * b) as i32;
+    #[test]
+    fn overrides_settings_of_scene() {
+        let settings = overridden(&["--width", "320", "--height", "200", "--spp", "8", "--seed", "7", "-o", "out.pfm"]).unwrap();
+        assert_eq!((settings.width, settings.height, settings.samples), (320, 200, 8));
+        assert_eq!(settings.seed, Some(7));
+        assert_eq!(settings.output, std::path::PathBuf::from("out.pfm"));
 
-        write!(writer, "{} {} {}\n", r, g, b)?;
+        let defaults = RenderSettings::default();
+        let settings = overridden(&[]).unwrap();
+        assert_eq!((settings.width, settings.height, settings.samples), (defaults.width, defaults.height, defaults.samples));
     }
 
-    Ok(())
+    #[test]
+    fn rejects_invalid_overrides() {
+        assert!(overridden(&["--spp", "0"]).is_err());
+        assert!(overridden(&["--width", "wide"]).is_err());
+        assert!(overridden(&["--max-depth", "-1"]).is_err());
+        assert!(overridden(&["--max-depth", "4", "--min-depth", "5"]).is_err());
+        // Lowering the maximum below the minimum of the scene does not silently lower the minimum.
+        assert!(overridden(&["--max-depth", "2"]).is_err());
+        assert!(overridden(&["--max-depth", "2", "--min-depth", "2"]).is_ok());
+        assert!(overridden(&["--format", "png"]).is_err());
+    }
 }
@@ -1,50 +1,142 @@
 use crate::ray::Ray;
 use crate::hitable::HitRecord;
 use crate::geometry::Vec3;
-use crate::random_in_unit_sphere;
+use crate::texture::Texture;
+use crate::rng::{random_unit_vector, random_in_unit_sphere};
+use crate::rng;
+use std::f32::consts::PI;
+use std::sync::Arc;
 
-pub(crate) struct Scattered {
-    pub(crate) attenuation: Vec3,
-    pub(crate) scattered: Ray,
+pub struct Scattered {
+    pub attenuation: Vec3,
+    pub scattered: Ray,
 }
 
-pub(crate) trait Material: Send + Sync {
+pub trait Material: Send + Sync {
     fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scattered>;
+
+    /// Fraction of light arriving from `direction` that is scattered towards the origin of the ray,
+    /// multiplied by the cosine of the angle to the surface normal. Returns `None` for materials
+    /// that scatter only into discrete directions, like mirrors, which lights cannot be sampled for.
+    fn eval(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: Vec3) -> Option<Vec3> {
+        None
+    }
+
+    /// Probability density with respect to solid angle of `scatter` choosing `direction`.
+    /// Zero for materials that scatter only into discrete directions.
+    fn pdf(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: Vec3) -> f32 {
+        0.0
+    }
+
+    /// Radiance emitted from the hit point towards the origin of the ray.
+    fn emitted(&self, _ray: &Ray, _hit_record: &HitRecord) -> Vec3 {
+        Vec3::zeros()
+    }
+}
+
+pub struct Lambertian {
+    pub albedo: Arc<dyn Texture>,
 }
 
-pub(crate) struct Lambertian {
-    pub(crate) albedo: Vec3,
+/// Surface normal on the side from which the ray arrives.
+fn facing_normal(ray: &Ray, hit_record: &HitRecord) -> Vec3 {
+    if Vec3::dot(ray.direction, hit_record.normal) > 0.0 {
+        -hit_record.normal
+    } else {
+        hit_record.normal
+    }
 }
 
 impl Material for Lambertian {
     fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scattered> {
-        let target = hit_record.point + hit_record.normal + random_in_unit_sphere();
+        let target = hit_record.point + facing_normal(ray, hit_record) + random_unit_vector();
         Some(Scattered {
-            attenuation: self.albedo,
-            scattered: Ray::new(hit_record.point, target - hit_record.point),
+            attenuation: self.albedo.at(hit_record),
+            scattered: Ray::at_time(hit_record.point, target - hit_record.point, ray.time),
         })
     }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> Option<Vec3> {
+        let cosine = Vec3::dot(facing_normal(ray, hit_record), direction).max(0.0);
+        Some(self.albedo.at(hit_record) * (cosine / PI))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> f32 {
+        Vec3::dot(facing_normal(ray, hit_record), direction).max(0.0) / PI
+    }
 }
 
-pub(crate) struct Metal {
-    pub(crate) albedo: Vec3,
+/// Mirror-like reflection, blurred by perturbing the reflected direction within a sphere
+/// of radius `fuzz`, from 0 for a perfect mirror to 1 for a satin finish.
+pub struct Metal {
+    pub albedo: Arc<dyn Texture>,
+    pub fuzz: Arc<dyn Texture>,
 }
 
 fn reflect(v: Vec3, n: Vec3) -> Vec3 {
     v - 2.0 * Vec3::dot(v, n) * n
 }
 
+/// Density with respect to solid angle of the direction towards a point distributed uniformly
+/// in a sphere of `radius` around the unit vector `center`. Points along `direction` lie on
+/// a chord of the sphere, over which the density is integrated.
+fn perturbed_pdf(direction: Vec3, center: Vec3, radius: f32) -> f32 {
+    let cosine = Vec3::dot(direction, center);
+    let sin_squared = 1.0 - cosine * cosine;
+    if sin_squared >= radius * radius {
+        return 0.0;
+    }
+    let half_chord = (radius * radius - sin_squared).sqrt();
+    let far = cosine + half_chord;
+    if far <= 0.0 {
+        return 0.0;
+    }
+    let near = (cosine - half_chord).max(0.0);
+    (far * far * far - near * near * near) / (4.0 * PI * radius * radius * radius)
+}
+
+impl Metal {
+    /// Density of `scatter` choosing `direction`, including directions folded back above
+    /// the surface. Zero for a perfect mirror, which has none.
+    fn fuzz_pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> f32 {
+        let fuzz = self.fuzz.scalar_at(hit_record);
+        let normal = facing_normal(ray, hit_record).normalize();
+        let cosine = Vec3::dot(direction, normal);
+        if fuzz <= 0.0 || cosine <= 0.0 {
+            return 0.0;
+        }
+        let reflected = reflect(ray.direction.normalize(), normal);
+        perturbed_pdf(direction, reflected, fuzz) + perturbed_pdf(direction - 2.0 * cosine * normal, reflected, fuzz)
+    }
+}
+
 impl Material for Metal {
     fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scattered> {
-        let reflected = reflect(ray.direction.normalize(), hit_record.normal);
-        if Vec3::dot(reflected, hit_record.normal) > 0.0 {
-            Some(Scattered {
-                attenuation: self.albedo,
-                scattered: Ray::new(hit_record.point, reflected),
-            })
-        } else {
-            None
+        let normal = facing_normal(ray, hit_record);
+        let mut reflected = reflect(ray.direction.normalize(), normal) + self.fuzz.scalar_at(hit_record) * random_in_unit_sphere();
+        // Directions perturbed below the surface at grazing angles are folded back above it
+        // instead of being absorbed, which would darken rough metals towards their silhouettes.
+        let below = Vec3::dot(reflected, normal);
+        if below < 0.0 {
+            reflected = reflected - 2.0 * below * normal;
         }
+        Some(Scattered {
+            attenuation: self.albedo.at(hit_record),
+            scattered: Ray::at_time(hit_record.point, reflected, ray.time),
+        })
+    }
+
+    /// Scattered directions are all weighted by the albedo, so the reflection is the albedo
+    /// times the density of choosing them.
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> Option<Vec3> {
+        if self.fuzz.scalar_at(hit_record) <= 0.0 {
+            return None;
+        }
+        Some(self.albedo.at(hit_record) * self.fuzz_pdf(ray, hit_record, direction))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> f32 {
+        self.fuzz_pdf(ray, hit_record, direction)
     }
 }
 
@@ -59,30 +151,31 @@ fn refract(v: Vec3, n: Vec3, ni_over_nt: f32) -> Option<Vec3> {
     }
 }
 
-pub(crate) struct Dielectric {
-    pub(crate) ref_idx: f32,
+pub struct Dielectric {
+    pub ref_idx: Arc<dyn Texture>,
 }
 
 impl Material for Dielectric {
     fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scattered> {
+        let ref_idx = self.ref_idx.scalar_at(hit_record);
         let (outward_normal, ni_over_nt, cosine) = if Vec3::dot(ray.direction, hit_record.normal) > 0.0 {
             (
                 -hit_record.normal,
-                self.ref_idx,
-                self.ref_idx * Vec3::dot(ray.direction, hit_record.normal) / ray.direction.length()
+                ref_idx,
+                ref_idx * Vec3::dot(ray.direction, hit_record.normal) / ray.direction.length()
             )
         } else {
             (
                 hit_record.normal,
-                1.0 / self.ref_idx,
+                1.0 / ref_idx,
                 -Vec3::dot(ray.direction, hit_record.normal) / ray.direction.length()
             )
         };
 
         let direction = match refract(ray.direction, outward_normal, ni_over_nt) {
             Some(refracted) => {
-                let reflect_prob = schlick(cosine, self.ref_idx);
-                if rand::random::<f32>() < reflect_prob {
+                let reflect_prob = schlick(cosine, ref_idx);
+                if rng::random::<f32>() < reflect_prob {
                     reflect(ray.direction, hit_record.normal)
                 } else {
                     refracted
@@ -93,7 +186,7 @@ impl Material for Dielectric {
 
         Some(Scattered {
             attenuation: Vec3::new(1.0, 1.0, 1.0),
-            scattered: Ray::new(hit_record.point, direction),
+            scattered: Ray::at_time(hit_record.point, direction, ray.time),
         })
     }
 }
@@ -102,4 +195,105 @@ fn schlick(cosine: f32, ref_idx: f32) -> f32 {
     let r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
     let r0 = r0 * r0;
     r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
-}
\ No newline at end of file
+}
+
+/// Area light emitting the same radiance from both sides of the surface, in every direction.
+pub struct DiffuseLight {
+    pub emit: Arc<dyn Texture>,
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray: &Ray, _hit_record: &HitRecord) -> Option<Scattered> {
+        None
+    }
+
+    fn emitted(&self, _ray: &Ray, hit_record: &HitRecord) -> Vec3 {
+        self.emit.at(hit_record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::ConstantTexture;
+
+    fn metal(fuzz: f32) -> Arc<dyn Material> {
+        Arc::new(Metal {
+            albedo: Arc::new(ConstantTexture::new(Vec3::new(0.9, 0.6, 0.3))),
+            fuzz: Arc::new(ConstantTexture::gray(fuzz)),
+        })
+    }
+
+    fn hit(material: Arc<dyn Material>) -> HitRecord {
+        HitRecord { t: 1.0, point: Vec3::zeros(), normal: Vec3::new(0.0, 0.0, 1.0), u: 0.0, v: 0.0, footprint: 0.0, material }
+    }
+
+    #[test]
+    fn fuzzy_metal_pdf_matches_scattered_directions() {
+        for &fuzz in &[0.3, 1.0] {
+            for &incoming in &[Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.9, 0.0, -0.2).normalize()] {
+                let material = metal(fuzz);
+                let record = hit(material.clone());
+                let ray = Ray::new(-incoming, incoming);
+
+                // Fraction of samples in bands around the normal against the integral of the pdf.
+                let bands = 8;
+                let count = 50_000;
+                let mut sampled = vec![0.0; bands];
+                for _ in 0..count {
+                    let Scattered { attenuation, scattered } = material.scatter(&ray, &record).unwrap();
+                    let direction = scattered.direction.normalize();
+                    let expected = material.eval(&ray, &record, direction).unwrap() / material.pdf(&ray, &record, direction);
+                    assert!((attenuation - expected).length() < 1e-4);
+                    let band = ((direction.z() * bands as f32) as usize).min(bands - 1);
+                    sampled[band] += 1.0 / count as f32;
+                }
+
+                let (rows, columns) = (800, 400);
+                let mut integrated = vec![0.0; bands];
+                let mut total = 0.0;
+                for i in 0..rows {
+                    let z = -1.0 + 2.0 * (i as f32 + 0.5) / rows as f32;
+                    let r = (1.0 - z * z).sqrt();
+                    for j in 0..columns {
+                        let phi = 2.0 * PI * (j as f32 + 0.5) / columns as f32;
+                        let direction = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+                        let mass = material.pdf(&ray, &record, direction) * 4.0 * PI / (rows * columns) as f32;
+                        total += mass;
+                        if z > 0.0 {
+                            integrated[((z * bands as f32) as usize).min(bands - 1)] += mass;
+                        }
+                    }
+                }
+                assert!((total - 1.0).abs() < 0.02, "{} {}", fuzz, total);
+                for (sampled, integrated) in sampled.iter().zip(&integrated) {
+                    assert!((sampled - integrated).abs() < 0.02, "{} {:?} {:?}", fuzz, sampled, integrated);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rough_metal_at_grazing_angles_reflects_above_surface() {
+        let material = metal(1.0);
+        let record = hit(material.clone());
+        let incoming = Vec3::new(1.0, 0.0, -0.01).normalize();
+        let ray = Ray::new(-incoming, incoming);
+        // Without the fold, about half of the perturbed directions would point into the surface.
+        for _ in 0..10_000 {
+            let scattered = material.scatter(&ray, &record).expect("grazing rays must not be absorbed");
+            let direction = scattered.scattered.direction;
+            assert!(direction.z() >= 0.0, "{:?}", direction.raw);
+            assert_eq!(scattered.attenuation.raw, [0.9, 0.6, 0.3]);
+        }
+    }
+
+    #[test]
+    fn mirror_has_no_density() {
+        let material = metal(0.0);
+        let record = hit(material.clone());
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(material.eval(&ray, &record, Vec3::new(0.0, 0.0, 1.0)).is_none());
+        assert_eq!(material.pdf(&ray, &record, Vec3::new(0.0, 0.0, 1.0)), 0.0);
+    }
+}
@@ -1,202 +1,283 @@
 use crate::geometry::Vec3;
-use crate::ray::Ray;
+use crate::aabb::Aabb;
+use crate::mesh_bvh::MeshBvh;
 
-pub(crate) struct Mesh {
+pub struct Mesh {
     vertices: Vec<Vec3>,
     normals: Vec<Vec3>,
     triangles: Vec<(u32, u32, u32)>,
-    triangles_normals: Vec<(u32, u32, u32)>,
+    triangles_normals: Vec<Option<(u32, u32, u32)>>,
+    uvs: Vec<(f32, f32)>,
+    triangles_uvs: Vec<Option<(u32, u32, u32)>>,
     aabb: Aabb,
+    bvh: MeshBvh,
 }
 
-#[derive(Copy, Clone)]
-#[derive(Debug)]
-pub(crate) struct Aabb {
-    min_x: f32,
-    max_x: f32,
-    min_y: f32,
-    max_y: f32,
-    min_z: f32,
-    max_z: f32,
+pub struct MeshBuilder {
+    vertices: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    triangles: Vec<(u32, u32, u32)>,
+    triangles_normals: Vec<Option<(u32, u32, u32)>>,
+    uvs: Vec<(f32, f32)>,
+    triangles_uvs: Vec<Option<(u32, u32, u32)>>,
 }
 
-impl Default for Aabb {
+impl Default for MeshBuilder {
     fn default() -> Self {
-        Self {
-            min_x: std::f32::MAX,
-            max_x: std::f32::MIN,
-            min_y: std::f32::MAX,
-            max_y: std::f32::MIN,
-            min_z: std::f32::MAX,
-            max_z: std::f32::MIN,
-        }
+        Self::new()
     }
 }
 
-pub(crate) struct MeshBuilder {
-    vertices: Vec<Vec3>,
-    normals: Vec<Vec3>,
-    triangles: Vec<(u32, u32, u32)>,
-    triangles_normals: Vec<(u32, u32, u32)>,
-}
-
 impl MeshBuilder {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             vertices: vec![],
             normals: vec![],
             triangles: vec![],
             triangles_normals: vec![],
+            uvs: vec![],
+            triangles_uvs: vec![],
         }
     }
 
-    pub(crate) fn push_normal(&mut self, n: Vec3) -> NormalIndex {
+    pub fn push_normal(&mut self, n: Vec3) -> NormalIndex {
         let idx = self.normals.len() as u32;
         self.normals.push(n);
         NormalIndex(idx)
     }
 
-    pub(crate) fn push_vertex(&mut self, v: Vec3) -> VertexIndex {
+    pub fn push_uv(&mut self, u: f32, v: f32) -> UvIndex {
+        let idx = self.uvs.len() as u32;
+        self.uvs.push((u, v));
+        UvIndex(idx)
+    }
+
+    pub fn push_vertex(&mut self, v: Vec3) -> VertexIndex {
         let idx = self.vertices.len() as u32;
         self.vertices.push(v);
         VertexIndex(idx)
     }
 
-    pub(crate) fn push_face(
+    pub fn push_face(
         &mut self,
         v0: VertexIndex, n0: NormalIndex,
         v1: VertexIndex, n1: NormalIndex,
         v2: VertexIndex, n2: NormalIndex,
     ) {
         self.triangles.push((v0.0, v1.0, v2.0));
-        self.triangles_normals.push((n0.0, n1.0, n2.0))
+        self.triangles_normals.push(Some((n0.0, n1.0, n2.0)));
+        self.triangles_uvs.push(None)
     }
 
-    pub(crate) fn build(self) -> Mesh {
+    /// Adds a face without vertex normals, which is shaded using its geometric normal
+    /// unless `generate_smooth_normals` is called.
+    pub fn push_triangle(&mut self, v0: VertexIndex, v1: VertexIndex, v2: VertexIndex) {
+        self.triangles.push((v0.0, v1.0, v2.0));
+        self.triangles_normals.push(None);
+        self.triangles_uvs.push(None)
+    }
+
+    /// Assigns texture coordinates to the corners of the face added last. Faces without them
+    /// map their first, second and third corner to `(0, 0)`, `(1, 0)` and `(1, 1)`.
+    ///
+    /// Fails when no face has been added yet.
+    pub fn set_face_uvs(&mut self, t0: UvIndex, t1: UvIndex, t2: UvIndex) -> Result<(), &'static str> {
+        let uvs = self.triangles_uvs.last_mut().ok_or("texture coordinates set before adding a face")?;
+        *uvs = Some((t0.0, t1.0, t2.0));
+        Ok(())
+    }
+
+    /// Assigns vertex normals to all faces that do not have them. Normal of each vertex is
+    /// the average of normals of faces sharing it, weighted by the angle of the face at that vertex.
+    pub fn generate_smooth_normals(&mut self) {
+        let mut accumulated = vec![Vec3::zeros(); self.vertices.len()];
+        for (&(i0, i1, i2), normals) in self.triangles.iter().zip(&self.triangles_normals) {
+            if normals.is_some() {
+                continue;
+            }
+
+            let p = [self.vertices[i0 as usize], self.vertices[i1 as usize], self.vertices[i2 as usize]];
+            let face_normal = Vec3::cross(p[1] - p[0], p[2] - p[0]);
+            if face_normal.squared_len() == 0.0 {
+                continue;
+            }
+            let face_normal = face_normal.normalize();
+
+            for (corner, &i) in [i0, i1, i2].iter().enumerate() {
+                let e0 = p[(corner + 1) % 3] - p[corner];
+                let e1 = p[(corner + 2) % 3] - p[corner];
+                let cos = Vec3::dot(e0, e1) / (e0.length() * e1.length());
+                let angle = cos.clamp(-1.0, 1.0).acos();
+                if angle.is_finite() {
+                    accumulated[i as usize] = accumulated[i as usize] + angle * face_normal;
+                }
+            }
+        }
+
+        let first = self.normals.len() as u32;
+        for n in accumulated {
+            let n = if n.squared_len() > 0.0 { n.normalize() } else { n };
+            self.normals.push(n);
+        }
+
+        for (&(i0, i1, i2), normals) in self.triangles.iter().zip(self.triangles_normals.iter_mut()) {
+            if normals.is_none() {
+                *normals = Some((first + i0, first + i1, first + i2));
+            }
+        }
+    }
+
+    pub fn build(self) -> Mesh {
         let mut aabb = Aabb::default();
         for v in &self.vertices {
-            aabb.min_x = aabb.min_x.min(v.x());
-            aabb.max_x = aabb.max_x.max(v.x());
-            aabb.min_y = aabb.min_y.min(v.y());
-            aabb.max_y = aabb.max_y.max(v.y());
-            aabb.min_z = aabb.min_z.min(v.z());
-            aabb.max_z = aabb.max_z.max(v.z());
+            aabb.grow(*v);
         }
 
-        dbg!(&aabb);
+        let (bvh, order) = MeshBvh::build(&self.vertices, &self.triangles);
+        let triangles = order.iter().map(|&i| self.triangles[i as usize]).collect();
+        let triangles_normals = order.iter().map(|&i| self.triangles_normals[i as usize]).collect();
+        let triangles_uvs = order.iter().map(|&i| self.triangles_uvs[i as usize]).collect();
 
         Mesh {
             vertices: self.vertices,
             normals: self.normals,
-            triangles: self.triangles,
-            triangles_normals: self.triangles_normals,
+            triangles,
+            triangles_normals,
+            uvs: self.uvs,
+            triangles_uvs,
             aabb,
+            bvh,
         }
     }
 }
 
 #[derive(Copy, Clone)]
-pub(crate) struct VertexIndex(u32);
+pub struct VertexIndex(u32);
 
 #[derive(Copy, Clone)]
-pub(crate) struct NormalIndex(u32);
+pub struct NormalIndex(u32);
+
+#[derive(Copy, Clone)]
+pub struct UvIndex(u32);
+
+impl Default for Mesh {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Mesh {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             vertices: vec![],
             normals: vec![],
             triangles: vec![],
             triangles_normals: vec![],
+            uvs: vec![],
+            triangles_uvs: vec![],
             aabb: Aabb::default(),
+            bvh: MeshBvh::build(&[], &[]).0,
         }
     }
 
-    pub(crate) fn aabb(&self) -> Aabb {
+    /// Uniformly scales the mesh and then moves it by `translation`.
+    pub fn transformed(mut self, scale: f32, translation: Vec3) -> Mesh {
+        for v in &mut self.vertices {
+            *v = scale * *v + translation;
+        }
+        self.aabb = self.aabb.transformed(scale, translation);
+        self.bvh.transform(scale, translation);
+        self
+    }
+
+    pub fn aabb(&self) -> Aabb {
         self.aabb
     }
 
-    pub(crate) fn vertices(&self) -> &[Vec3] {
+    pub fn vertices(&self) -> &[Vec3] {
         &self.vertices
     }
 
-    pub(crate) fn normals(&self) -> &[Vec3] {
+    pub fn normals(&self) -> &[Vec3] {
         &self.normals
     }
 
-    pub(crate) fn triangles(&self) -> &[(u32, u32, u32)] {
+    pub fn triangles(&self) -> &[(u32, u32, u32)] {
         &self.triangles
     }
 
-    pub(crate) fn iter_triangles<'a>(&'a self) -> impl Iterator<Item=(Vertex, Vertex, Vertex)> + 'a {
-        self.triangles
-            .iter()
-            .enumerate()
-            .map(move |(i, &(i0, i1, i2))| {
-                let (n0, n1, n2) = self.triangles_normals[i];
-                (
-                    Vertex { position: self.vertices[i0 as usize], normal: self.normals[n0 as usize] },
-                    Vertex { position: self.vertices[i1 as usize], normal: self.normals[n1 as usize] },
-                    Vertex { position: self.vertices[i2 as usize], normal: self.normals[n2 as usize] },
-                )
-            })
+    pub(crate) fn bvh(&self) -> &MeshBvh {
+        &self.bvh
     }
-}
-
-#[derive(Copy, Clone)]
-pub(crate) struct Vertex {
-    pub(crate) position: Vec3,
-    pub(crate) normal: Vec3,
-}
-
-pub(crate) fn box_intersect(ray: &Ray, aabb: &Aabb) -> bool {
-    // Based on
-    // https://www.scratchapixel.com/lessons/3d-basic-rendering/minimal-ray-tracer-rendering-simple-shapes/ray-box-intersection
 
-    let mut tmin = (aabb.min_x - ray.origin.x()) / ray.direction.x();
-    let mut tmax = (aabb.max_x - ray.origin.x()) / ray.direction.x();
-
-    if tmin > tmax {
-        std::mem::swap(&mut tmin, &mut tmax);
-    }
-
-    let mut tymin = (aabb.min_y - ray.origin.y()) / ray.direction.y();
-    let mut tymax = (aabb.max_y - ray.origin.y()) / ray.direction.y();
-
-    if tymin > tymax {
-        std::mem::swap(&mut tymin, &mut tymax);
+    pub fn triangle(&self, i: usize) -> (Vertex, Vertex, Vertex) {
+        let (i0, i1, i2) = self.triangles[i];
+        let (n0, n1, n2) = match self.triangles_normals[i] {
+            Some((n0, n1, n2)) => (
+                Some(self.normals[n0 as usize]),
+                Some(self.normals[n1 as usize]),
+                Some(self.normals[n2 as usize]),
+            ),
+            None => (None, None, None),
+        };
+        let (t0, t1, t2) = match self.triangles_uvs[i] {
+            Some((t0, t1, t2)) => (self.uvs[t0 as usize], self.uvs[t1 as usize], self.uvs[t2 as usize]),
+            None => ((0.0, 0.0), (1.0, 0.0), (1.0, 1.0)),
+        };
+        (
+            Vertex { position: self.vertices[i0 as usize], normal: n0, uv: t0 },
+            Vertex { position: self.vertices[i1 as usize], normal: n1, uv: t1 },
+            Vertex { position: self.vertices[i2 as usize], normal: n2, uv: t2 },
+        )
     }
 
-    if tmin > tymax || tymin > tmax {
-        return false;
+    pub fn iter_triangles<'a>(&'a self) -> impl Iterator<Item=(Vertex, Vertex, Vertex)> + 'a {
+        (0..self.triangles.len()).map(move |i| self.triangle(i))
     }
+}
 
-    if tymin > tmin {
-        tmin = tymin;
-    }
+#[derive(Copy, Clone)]
+pub struct Vertex {
+    pub position: Vec3,
+    pub normal: Option<Vec3>,
+    /// Texture coordinates of the vertex.
+    pub uv: (f32, f32),
+}
 
-    if tymax < tmax {
-        tmax = tymax;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smooth_normals_are_weighted_by_angle() {
+        let mut builder = MeshBuilder::new();
+        let origin = builder.push_vertex(Vec3::zeros());
+        let x = builder.push_vertex(Vec3::new(1.0, 0.0, 0.0));
+        let y = builder.push_vertex(Vec3::new(0.0, 1.0, 0.0));
+        let z = builder.push_vertex(Vec3::new(0.0, 0.0, 1.0));
+        let xz = builder.push_vertex(Vec3::new(1.0, 0.0, 1.0));
+        // Facing +z with a right angle at the origin.
+        builder.push_triangle(origin, x, y);
+        // Facing +y with half of that angle.
+        builder.push_triangle(origin, z, xz);
+        builder.generate_smooth_normals();
+
+        let expected = Vec3::new(0.0, 1.0, 2.0).normalize();
+        let normal = builder.normals[0];
+        assert!((normal - expected).length() < 1e-6, "{:?}", normal.raw);
+        // Vertices used by a single face take its normal.
+        assert!((builder.normals[1] - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-6);
+        assert!((builder.normals[3] - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-6);
     }
 
-    let mut tzmin = (aabb.min_z - ray.origin.z()) / ray.direction.z();
-    let mut tzmax = (aabb.max_z - ray.origin.z()) / ray.direction.z();
-
-    if tzmin > tzmax {
-        std::mem::swap(&mut tzmin, &mut tzmax);
-    }
+    #[test]
+    fn face_uvs_need_a_face() {
+        let mut builder = MeshBuilder::new();
+        let t = builder.push_uv(0.0, 0.0);
+        assert!(builder.set_face_uvs(t, t, t).is_err());
 
-    if tmin > tzmax || tzmin > tmax {
-        return false;
+        let v = builder.push_vertex(Vec3::zeros());
+        builder.push_triangle(v, v, v);
+        assert!(builder.set_face_uvs(t, t, t).is_ok());
     }
-//
-//    if tzmin > tmin {
-//        tmin = tzmin;
-//    }
-//
-//    if tzmax < tmax {
-//        tmax = tzmax;
-//    }
-
-    true
-}
\ No newline at end of file
+}
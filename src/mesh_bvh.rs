@@ -0,0 +1,378 @@
+use crate::aabb::Aabb;
+use crate::geometry::Vec3;
+use crate::ray::Ray;
+
+/// Number of buckets used to evaluate split candidates along each axis.
+const BIN_COUNT: usize = 12;
+
+/// Relative cost of traversing a node compared to intersecting a single triangle.
+const TRAVERSAL_COST: f32 = 1.0;
+
+/// Leaves with more triangles than this are always split.
+const MAX_LEAF_SIZE: usize = 8;
+
+/// Depth after which the builder stops looking for good splits and just halves the range,
+/// which keeps the tree shallow enough for the fixed size traversal stack.
+const MAX_SAH_DEPTH: usize = 32;
+
+/// Traversal stack size, deep enough for any tree built from `u32` indexed triangles.
+const STACK_SIZE: usize = 64;
+
+/// Bounding volume hierarchy over triangles of a mesh, stored as a flat array of nodes
+/// in depth-first order, so that the left child of a branch always directly follows it.
+pub(crate) struct MeshBvh {
+    nodes: Vec<FlatNode>,
+}
+
+#[derive(Copy, Clone)]
+struct FlatNode {
+    aabb: Aabb,
+    /// First triangle for leaves, index of the right child for branches.
+    offset: u32,
+    /// Number of triangles in a leaf, zero for branches.
+    count: u32,
+}
+
+struct BuildItem {
+    aabb: Aabb,
+    centroid: Vec3,
+    index: u32,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Bin {
+    aabb: Aabb,
+    count: usize,
+}
+
+impl MeshBvh {
+    /// Builds the hierarchy using binned surface area heuristic. Returns the tree together with
+    /// the order in which triangles have to be stored, so that every leaf covers
+    /// a contiguous range of them.
+    pub(crate) fn build(vertices: &[Vec3], triangles: &[(u32, u32, u32)]) -> (MeshBvh, Vec<u32>) {
+        let mut items: Vec<BuildItem> = triangles.iter()
+            .enumerate()
+            .map(|(i, &(i0, i1, i2))| {
+                let mut aabb = Aabb::default();
+                aabb.grow(vertices[i0 as usize]);
+                aabb.grow(vertices[i1 as usize]);
+                aabb.grow(vertices[i2 as usize]);
+                BuildItem { aabb, centroid: aabb.centroid(), index: i as u32 }
+            })
+            .collect();
+
+        let mut bvh = MeshBvh { nodes: Vec::with_capacity(2 * items.len()) };
+        if !items.is_empty() {
+            bvh.build_recursive(&mut items, 0, 0);
+        }
+
+        let order = items.iter().map(|item| item.index).collect();
+        (bvh, order)
+    }
+
+    fn build_recursive(&mut self, items: &mut [BuildItem], offset: usize, depth: usize) -> usize {
+        let node_index = self.nodes.len();
+        let aabb = items.iter().fold(Aabb::default(), |aabb, item| Aabb::union(aabb, item.aabb));
+        self.nodes.push(FlatNode { aabb, offset: offset as u32, count: items.len() as u32 });
+
+        if items.len() <= 1 {
+            return node_index;
+        }
+
+        let mut centroid_bounds = Aabb::default();
+        for item in items.iter() {
+            centroid_bounds.grow(item.centroid);
+        }
+
+        let split = if depth < MAX_SAH_DEPTH {
+            find_split(items, aabb, centroid_bounds)
+        } else {
+            None
+        };
+
+        let mid = match split {
+            Some((axis, split)) => partition(items, |item| bin_index(item.centroid, &centroid_bounds, axis) < split),
+            None if items.len() > MAX_LEAF_SIZE => {
+                // Either all centroids are the same or the tree is already deep,
+                // so just cut the range in half.
+                items.len() / 2
+            }
+            None => return node_index,
+        };
+
+        let (left, right) = items.split_at_mut(mid);
+        self.build_recursive(left, offset, depth + 1);
+        let right_index = self.build_recursive(right, offset + mid, depth + 1);
+        self.nodes[node_index].offset = right_index as u32;
+        self.nodes[node_index].count = 0;
+        node_index
+    }
+
+    /// Updates bounds of all nodes after the triangles were uniformly scaled and translated,
+    /// which does not change the structure of the tree.
+    pub(crate) fn transform(&mut self, scale: f32, translation: Vec3) {
+        for node in &mut self.nodes {
+            node.aabb = node.aabb.transformed(scale, translation);
+        }
+    }
+
+    /// Visits leaves front to back and calls `intersect` for every triangle that may be hit.
+    /// The callback receives index of the triangle and the distance to the closest hit found so far,
+    /// and should return the distance to the new hit if it is closer.
+    pub(crate) fn traverse<F>(&self, ray: &Ray, t_min: f32, t_max: f32, intersect: F)
+        where F: FnMut(usize, f32) -> Option<f32> {
+        self.walk(ray, t_min, t_max, false, intersect);
+    }
+
+    /// Like `traverse`, but stops at the first triangle for which `intersect` reports a hit.
+    /// Returns whether such a triangle was found.
+    pub(crate) fn traverse_any<F>(&self, ray: &Ray, t_min: f32, t_max: f32, mut intersect: F) -> bool
+        where F: FnMut(usize) -> bool {
+        self.walk(ray, t_min, t_max, true, |i, t_max| if intersect(i) { Some(t_max) } else { None })
+    }
+
+    fn walk<F>(&self, ray: &Ray, t_min: f32, t_max: f32, any_hit: bool, mut intersect: F) -> bool
+        where F: FnMut(usize, f32) -> Option<f32> {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let mut closest_so_far = t_max;
+        let mut found = false;
+        let mut stack = [0u32; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut current = 0usize;
+
+        if !self.nodes[current].aabb.hit(ray, t_min, closest_so_far) {
+            return false;
+        }
+
+        loop {
+            let node = self.nodes[current];
+            if node.count > 0 {
+                for i in node.offset..node.offset + node.count {
+                    if let Some(t) = intersect(i as usize, closest_so_far) {
+                        if any_hit {
+                            return true;
+                        }
+                        closest_so_far = t;
+                        found = true;
+                    }
+                }
+            } else {
+                let left = current + 1;
+                let right = node.offset as usize;
+                let t_left = self.nodes[left].aabb.intersect(ray, t_min, closest_so_far);
+                let t_right = self.nodes[right].aabb.intersect(ray, t_min, closest_so_far);
+                match (t_left, t_right) {
+                    (Some(t_left), Some(t_right)) => {
+                        let (near, far) = if t_left <= t_right { (left, right) } else { (right, left) };
+                        stack[stack_len] = far as u32;
+                        stack_len += 1;
+                        current = near;
+                        continue;
+                    }
+                    (Some(_), None) => {
+                        current = left;
+                        continue;
+                    }
+                    (None, Some(_)) => {
+                        current = right;
+                        continue;
+                    }
+                    (None, None) => {}
+                }
+            }
+
+            // Pop nodes that are still in front of the closest hit.
+            loop {
+                if stack_len == 0 {
+                    return found;
+                }
+                stack_len -= 1;
+                current = stack[stack_len] as usize;
+                if self.nodes[current].aabb.hit(ray, t_min, closest_so_far) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn bin_index(centroid: Vec3, bounds: &Aabb, axis: usize) -> usize {
+    let min = bounds.min().raw[axis];
+    let extent = bounds.max().raw[axis] - min;
+    let relative = (centroid.raw[axis] - min) / extent;
+    ((relative * BIN_COUNT as f32) as usize).min(BIN_COUNT - 1)
+}
+
+/// Returns the axis and the first bin of the right child for the cheapest split,
+/// or `None` when a leaf should be created instead.
+fn find_split(items: &[BuildItem], aabb: Aabb, centroid_bounds: Aabb) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+    let mut best_cost = f32::INFINITY;
+
+    for axis in 0..3 {
+        if centroid_bounds.max().raw[axis] - centroid_bounds.min().raw[axis] <= 0.0 {
+            continue;
+        }
+
+        let mut bins = [Bin::default(); BIN_COUNT];
+        for item in items {
+            let bin = &mut bins[bin_index(item.centroid, &centroid_bounds, axis)];
+            bin.aabb = Aabb::union(bin.aabb, item.aabb);
+            bin.count += 1;
+        }
+
+        let mut right_costs = [0.0; BIN_COUNT];
+        let mut right = Bin::default();
+        for split in (1..BIN_COUNT).rev() {
+            right.aabb = Aabb::union(right.aabb, bins[split].aabb);
+            right.count += bins[split].count;
+            right_costs[split] = right.aabb.surface_area() * right.count as f32;
+        }
+
+        let mut left = Bin::default();
+        for split in 1..BIN_COUNT {
+            left.aabb = Aabb::union(left.aabb, bins[split - 1].aabb);
+            left.count += bins[split - 1].count;
+            if left.count == 0 || left.count == items.len() {
+                continue;
+            }
+            let cost = left.aabb.surface_area() * left.count as f32 + right_costs[split];
+            if cost < best_cost {
+                best_cost = cost;
+                best = Some((axis, split));
+            }
+        }
+    }
+
+    let leaf_cost = items.len() as f32;
+    let area = aabb.surface_area();
+    let split_cost = if area > 0.0 { TRAVERSAL_COST + best_cost / area } else { TRAVERSAL_COST };
+    if items.len() <= MAX_LEAF_SIZE && leaf_cost <= split_cost {
+        None
+    } else {
+        best
+    }
+}
+
+/// Moves items matching the predicate to the front and returns their number.
+fn partition<F: Fn(&BuildItem) -> bool>(items: &mut [BuildItem], predicate: F) -> usize {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if predicate(&items[i]) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn random_point(rng: &mut StdRng, extent: f32) -> Vec3 {
+        Vec3::new(rng.gen_range(-extent, extent), rng.gen_range(-extent, extent), rng.gen_range(-extent, extent))
+    }
+
+    /// Soup of small triangles scattered over a cube, so that most of the tree is culled.
+    fn random_triangles(rng: &mut StdRng) -> (Vec<Vec3>, Vec<(u32, u32, u32)>) {
+        let mut vertices = Vec::new();
+        let mut triangles = Vec::new();
+        for i in 0..500 {
+            let center = random_point(rng, 10.0);
+            vertices.push(center + random_point(rng, 0.5));
+            vertices.push(center + random_point(rng, 0.5));
+            vertices.push(center + random_point(rng, 0.5));
+            triangles.push((3 * i, 3 * i + 1, 3 * i + 2));
+        }
+        (vertices, triangles)
+    }
+
+    /// Möller–Trumbore, kept independent from the intersection used by meshes.
+    fn intersect(ray: &Ray, v0: Vec3, v1: Vec3, v2: Vec3) -> Option<f32> {
+        let e1 = v1 - v0;
+        let e2 = v2 - v0;
+        let p = Vec3::cross(ray.direction, e2);
+        let det = Vec3::dot(e1, p);
+        if det.abs() < 1e-8 {
+            return None;
+        }
+        let s = ray.origin - v0;
+        let u = Vec3::dot(s, p) / det;
+        let q = Vec3::cross(s, e1);
+        let v = Vec3::dot(ray.direction, q) / det;
+        if u < 0.0 || v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        Some(Vec3::dot(e2, q) / det)
+    }
+
+    fn vertices_of(vertices: &[Vec3], (i0, i1, i2): (u32, u32, u32)) -> (Vec3, Vec3, Vec3) {
+        (vertices[i0 as usize], vertices[i1 as usize], vertices[i2 as usize])
+    }
+
+    #[test]
+    fn agrees_with_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let (vertices, triangles) = random_triangles(&mut rng);
+        let (bvh, order) = MeshBvh::build(&vertices, &triangles);
+        let ordered: Vec<_> = order.iter().map(|&i| triangles[i as usize]).collect();
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = random_point(&mut rng, 15.0);
+            let ray = Ray::new(origin, random_point(&mut rng, 8.0) - origin);
+            let t_max = rng.gen_range(0.5, 2.0);
+
+            let expected = triangles.iter()
+                .filter_map(|&triangle| {
+                    let (v0, v1, v2) = vertices_of(&vertices, triangle);
+                    intersect(&ray, v0, v1, v2)
+                })
+                .filter(|&t| t > 0.001 && t < t_max)
+                .fold(None, |closest: Option<f32>, t| Some(closest.map(|c| c.min(t)).unwrap_or(t)));
+
+            let mut actual = None;
+            bvh.traverse(&ray, 0.001, t_max, |i, closest_so_far| {
+                let (v0, v1, v2) = vertices_of(&vertices, ordered[i]);
+                let t = intersect(&ray, v0, v1, v2)?;
+                if !(t > 0.001 && t < closest_so_far) {
+                    return None;
+                }
+                actual = Some(t);
+                Some(t)
+            });
+
+            assert_eq!(actual, expected);
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 100 && hits < 1900, "{}", hits);
+    }
+
+    #[test]
+    fn rays_pointing_away_miss() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let (vertices, triangles) = random_triangles(&mut rng);
+        let (bvh, _) = MeshBvh::build(&vertices, &triangles);
+
+        for _ in 0..100 {
+            let direction = random_point(&mut rng, 1.0);
+            let direction = direction / direction.length();
+            let ray = Ray::new(20.0 * direction, direction);
+            bvh.traverse(&ray, 0.001, f32::INFINITY, |_, _| panic!("visited a triangle behind the ray"));
+        }
+    }
+
+    #[test]
+    fn empty_mesh_has_no_hits() {
+        let (bvh, order) = MeshBvh::build(&[], &[]);
+        assert!(order.is_empty());
+        let ray = Ray::new(Vec3::zeros(), Vec3::new(0.0, 0.0, 1.0));
+        bvh.traverse(&ray, 0.001, f32::INFINITY, |_, _| panic!("empty tree has no triangles"));
+    }
+}
@@ -1,56 +1,119 @@
-use crate::mesh::{Mesh, VertexIndex, MeshBuilder, NormalIndex};
+use crate::mesh::{Mesh, VertexIndex, MeshBuilder, NormalIndex, UvIndex};
 use crate::geometry::Vec3;
 use std::path::Path;
-use wavefront_obj::obj::{Shape, Primitive};
+use std::error::Error;
+use wavefront_obj::obj::Primitive;
 
-pub(crate) fn generate_test_mesh(radius: f32, position: Vec3) -> Mesh {
+pub fn generate_test_mesh(radius: f32, position: Vec3) -> Mesh {
     let mut builder = MeshBuilder::new();
     let v0 = builder.push_vertex(Vec3::new(position.x(), position.y() + radius, position.z()));
     let v1 = builder.push_vertex(Vec3::new(position.x() - radius, position.y(), position.z()));
     let v2 = builder.push_vertex(Vec3::new(position.x() + radius, position.y(), position.z()));
     let v3 = builder.push_vertex(Vec3::new(position.x(), position.y(), position.z() + radius));
-    let n = builder.push_normal(Vec3::zeros());
-    builder.push_face(v0, n, v1, n, v2, n);
-    builder.push_face(v0, n, v1, n, v3, n);
-    builder.push_face(v2, n, v0, n, v3, n);
-    builder.push_face(v1, n, v2, n, v3, n);
+    builder.push_triangle(v0, v1, v2);
+    builder.push_triangle(v0, v1, v3);
+    builder.push_triangle(v2, v0, v3);
+    builder.push_triangle(v1, v2, v3);
     builder.build()
 }
 
-pub(crate) fn load_obj<P: AsRef<Path>>(path: P) -> Mesh {
-    let content = std::fs::read_to_string(path).unwrap();
-    let obj = wavefront_obj::obj::parse(content).unwrap();
-    let object = &obj.objects[0];
+/// Loads the first object from a Wavefront OBJ file. Faces without normals are flat shaded,
+/// unless `smooth_normals` is set, in which case vertex normals are generated for them.
+/// Texture coordinates are kept for faces that have them at all three corners.
+pub fn load_obj<P: AsRef<Path>>(path: P, smooth_normals: bool) -> Result<Mesh, Box<dyn Error>> {
+    let content = std::fs::read_to_string(&path)?;
+    let obj = wavefront_obj::obj::parse(content)
+        .map_err(|e| format!("{}:{}: {}", path.as_ref().display(), e.line_number, e.message))?;
+    let object = obj.objects.first()
+        .ok_or_else(|| format!("{}: no objects found", path.as_ref().display()))?;
     let mut mesh = MeshBuilder::new();
 
-    let vertices: Vec<VertexIndex> = object.vertices.iter().enumerate()
-        .map(|(i, v)| mesh.push_vertex(Vec3::new(v.x as f32, v.y as f32, v.z as f32)))
+    let vertices: Vec<VertexIndex> = object.vertices.iter()
+        .map(|v| mesh.push_vertex(Vec3::new(v.x as f32, v.y as f32, v.z as f32)))
         .collect();
 
     let normals: Vec<NormalIndex> = object.normals.iter()
         .map(|n| mesh.push_normal(Vec3::new(n.x as f32, n.y as f32, n.z as f32)))
         .collect();
-    let n0 = mesh.push_normal(Vec3::zeros());
 
-    let get_normal = |n: Option<usize>| n.and_then(|it| normals.get(it).copied()).unwrap_or(n0);
+    let uvs: Vec<UvIndex> = object.tex_vertices.iter()
+        .map(|t| mesh.push_uv(t.u as f32, t.v as f32))
+        .collect();
+
+    let get_normal = |n: Option<usize>| n.and_then(|it| normals.get(it).copied());
+    let get_uv = |t: Option<usize>| t.and_then(|it| uvs.get(it).copied());
 
     for g in &object.geometry {
         for shape in &g.shapes {
             match shape.primitive {
                 Primitive::Triangle(
-                    (v0, _, n0),
-                    (v1, _, n1),
-                    (v2, _, n2)
+                    (v0, t0, n0),
+                    (v1, t1, n1),
+                    (v2, t2, n2)
                 ) => {
-                    mesh.push_face(
-                        vertices[v0], get_normal(n0),
-                        vertices[v1], get_normal(n1),
-                        vertices[v2], get_normal(n2),
-                    );
+                    match (get_normal(n0), get_normal(n1), get_normal(n2)) {
+                        (Some(n0), Some(n1), Some(n2)) => mesh.push_face(
+                            vertices[v0], n0,
+                            vertices[v1], n1,
+                            vertices[v2], n2,
+                        ),
+                        _ => mesh.push_triangle(vertices[v0], vertices[v1], vertices[v2]),
+                    }
+                    if let (Some(t0), Some(t1), Some(t2)) = (get_uv(t0), get_uv(t1), get_uv(t2)) {
+                        mesh.set_face_uvs(t0, t1, t2)?;
+                    }
                 }
-                _ => unimplemented!()
+                _ => return Err(format!("{}: only triangle faces are supported", path.as_ref().display()).into()),
             }
         }
     }
-    mesh.build()
-}
\ No newline at end of file
+
+    if smooth_normals {
+        mesh.generate_smooth_normals();
+    }
+    Ok(mesh.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::Hitable;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::texture::ConstantTexture;
+    use crate::triangulated_model::TriangulatedModel;
+    use std::sync::Arc;
+
+    /// Unit square in the XY plane split into two triangles, the first one textured.
+    const SQUARE: &str = "\
+o square
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0
+v 1.0 1.0 0.0
+v 0.0 1.0 0.0
+vt 0.0 0.0
+vt 2.0 0.0
+vt 2.0 3.0
+f 1/1 2/2 3/3
+f 1 3 4
+";
+
+    #[test]
+    fn keeps_texture_coordinates() {
+        let path = std::env::temp_dir().join(format!("mrtx-square-{}.obj", std::process::id()));
+        std::fs::write(&path, SQUARE).unwrap();
+        let mesh = load_obj(&path, false);
+        std::fs::remove_file(&path).unwrap();
+        let model = TriangulatedModel::new(mesh.unwrap(), Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.5)) }));
+
+        let hit = |x: f32, y: f32| {
+            let record = model.hit(&Ray::new(Vec3::new(x, y, 1.0), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::INFINITY).unwrap();
+            (record.u, record.v)
+        };
+        let (u, v) = hit(0.75, 0.25);
+        assert!((u - 1.5).abs() < 1e-5 && (v - 0.75).abs() < 1e-5, "{} {}", u, v);
+        // The face without `vt` keeps the default coordinates within the unit square.
+        let (u, v) = hit(0.25, 0.75);
+        assert!((0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v), "{} {}", u, v);
+    }
+}
@@ -0,0 +1,455 @@
+use crate::geometry::Vec3;
+use crate::hitable::HitRecord;
+use crate::material::{Material, Scattered};
+use crate::ray::Ray;
+use crate::rng;
+use crate::texture::{Texture, ConstantTexture};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Orthonormal frame with the normal along z, in which microfacet models are evaluated.
+pub(crate) struct Frame {
+    tangent: Vec3,
+    bitangent: Vec3,
+    normal: Vec3,
+}
+
+impl Frame {
+    /// Frame around the surface normal on the side from which the ray arrives, together with
+    /// the direction towards the origin of the ray in that frame.
+    pub(crate) fn facing(ray: &Ray, hit_record: &HitRecord) -> (Frame, Vec3) {
+        let normal = hit_record.normal.normalize();
+        let towards_origin = -ray.direction.normalize();
+        let normal = if Vec3::dot(towards_origin, normal) < 0.0 { -normal } else { normal };
+        let (tangent, bitangent) = normal.orthonormal_basis();
+        let frame = Frame { tangent, bitangent, normal };
+        let wo = frame.to_local(towards_origin);
+        (frame, wo)
+    }
+
+    pub(crate) fn to_local(&self, v: Vec3) -> Vec3 {
+        Vec3::new(Vec3::dot(v, self.tangent), Vec3::dot(v, self.bitangent), Vec3::dot(v, self.normal))
+    }
+
+    pub(crate) fn to_world(&self, v: Vec3) -> Vec3 {
+        v.x() * self.tangent + v.y() * self.bitangent + v.z() * self.normal
+    }
+}
+
+/// Trowbridge-Reitz (GGX) distribution of microfacet normals around the z axis.
+#[derive(Copy, Clone)]
+pub(crate) struct Ggx {
+    alpha: f32,
+}
+
+impl Ggx {
+    /// Perceptual `roughness` from 0 to 1 is squared, so that it changes the look evenly.
+    /// Perfectly smooth surfaces are approximated with a tiny width, as they have no density.
+    pub(crate) fn from_roughness(roughness: f32) -> Self {
+        Self { alpha: (roughness * roughness).max(1e-3) }
+    }
+
+    fn d(self, h: Vec3) -> f32 {
+        if h.z() <= 0.0 {
+            return 0.0;
+        }
+        let alpha2 = self.alpha * self.alpha;
+        let denominator = h.z() * h.z() * (alpha2 - 1.0) + 1.0;
+        alpha2 / (PI * denominator * denominator)
+    }
+
+    /// Smith's auxiliary function, infinite for directions along the surface.
+    fn lambda(self, w: Vec3) -> f32 {
+        let cos2 = w.z() * w.z();
+        if cos2 <= 0.0 {
+            return f32::INFINITY;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        0.5 * ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0)
+    }
+
+    /// Fraction of microfacets facing `w` that are visible from it.
+    fn g1(self, w: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Fraction of microfacets visible from both directions, with heights taken into account.
+    fn g2(self, wo: Vec3, wi: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Chooses a microfacet normal in proportion to its visible area from `wo`
+    /// (Heitz, "Sampling the GGX Distribution of Visible Normals").
+    fn sample_visible(self, wo: Vec3) -> Vec3 {
+        let v = Vec3::new(self.alpha * wo.x(), self.alpha * wo.y(), wo.z()).normalize();
+        let length2 = v.x() * v.x() + v.y() * v.y();
+        let t1 = if length2 > 0.0 {
+            Vec3::new(-v.y(), v.x(), 0.0) / length2.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = Vec3::cross(v, t1);
+
+        let r = rng::random::<f32>().sqrt();
+        let phi = 2.0 * PI * rng::random::<f32>();
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + v.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let n = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * v;
+        Vec3::new(self.alpha * n.x(), self.alpha * n.y(), n.z().max(0.0)).normalize()
+    }
+
+    /// Density of `sample_visible` choosing `h`.
+    fn visible_pdf(self, wo: Vec3, h: Vec3) -> f32 {
+        self.g1(wo) * Vec3::dot(wo, h).max(0.0) * self.d(h) / wo.z()
+    }
+
+    /// Reflects `wo` on a visible microfacet, returning the direction and the microfacet normal.
+    /// Light reflected into the surface would scatter again between microfacets, which is
+    /// not modelled, so such directions are absorbed.
+    pub(crate) fn sample_reflection(self, wo: Vec3) -> Option<(Vec3, Vec3)> {
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let h = self.sample_visible(wo);
+        let wi = reflect(wo, h);
+        if wi.z() <= 0.0 {
+            return None;
+        }
+        Some((wi, h))
+    }
+
+    /// Reflection from `wi` towards `wo` times the cosine at `wi`, without the Fresnel term,
+    /// which is to be evaluated at the half vector of both directions.
+    pub(crate) fn reflection(self, wo: Vec3, wi: Vec3) -> f32 {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return 0.0;
+        }
+        let h = (wo + wi).normalize();
+        self.d(h) * self.g2(wo, wi) / (4.0 * wo.z())
+    }
+
+    /// Density of `sample_reflection` choosing `wi`.
+    pub(crate) fn reflection_pdf(self, wo: Vec3, wi: Vec3) -> f32 {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return 0.0;
+        }
+        let h = (wo + wi).normalize();
+        self.visible_pdf(wo, h) / (4.0 * Vec3::dot(wo, h))
+    }
+}
+
+fn reflect(wo: Vec3, h: Vec3) -> Vec3 {
+    2.0 * Vec3::dot(wo, h) * h - wo
+}
+
+/// Direction of light arriving along `wo` after passing through a facet with normal `h`
+/// into a medium with `eta` times the index of refraction, `None` on total internal reflection.
+fn refract(wo: Vec3, h: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = Vec3::dot(wo, h);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + (cos_i / eta - cos_t) * h)
+}
+
+/// Reflectance of an interface into a medium with `eta` times the index of refraction.
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+/// Reflectance of a conductor with complex index of refraction `eta + ik`.
+fn fresnel_conductor(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * a * cos_i;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rs + rp)
+}
+
+/// Rough metal reflecting according to its complex index of refraction `eta + ik`,
+/// given separately for red, green and blue.
+pub struct RoughConductor {
+    pub eta: Arc<dyn Texture>,
+    pub k: Arc<dyn Texture>,
+    /// From 0 for a polished surface to 1 for a very rough one.
+    pub roughness: Arc<dyn Texture>,
+}
+
+impl RoughConductor {
+    /// Conductor with optical constants of "gold", "copper", "aluminium" or "silver".
+    pub fn from_name(name: &str, roughness: Arc<dyn Texture>) -> Option<RoughConductor> {
+        let (eta, k) = match name {
+            "gold" => (Vec3::new(0.143, 0.374, 1.442), Vec3::new(3.983, 2.385, 1.603)),
+            "copper" => (Vec3::new(0.200, 0.924, 1.102), Vec3::new(3.912, 2.452, 2.142)),
+            "aluminium" => (Vec3::new(1.657, 0.880, 0.521), Vec3::new(9.224, 6.270, 4.837)),
+            "silver" => (Vec3::new(0.155, 0.117, 0.138), Vec3::new(4.828, 3.122, 2.147)),
+            _ => return None,
+        };
+        Some(RoughConductor {
+            eta: Arc::new(ConstantTexture::new(eta)),
+            k: Arc::new(ConstantTexture::new(k)),
+            roughness,
+        })
+    }
+
+    fn fresnel(&self, cos_i: f32, hit_record: &HitRecord) -> Vec3 {
+        let (eta, k) = (self.eta.at(hit_record), self.k.at(hit_record));
+        Vec3::new(
+            fresnel_conductor(cos_i, eta.x(), k.x()),
+            fresnel_conductor(cos_i, eta.y(), k.y()),
+            fresnel_conductor(cos_i, eta.z(), k.z()),
+        )
+    }
+}
+
+impl Material for RoughConductor {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scattered> {
+        let (frame, wo) = Frame::facing(ray, hit_record);
+        let ggx = Ggx::from_roughness(self.roughness.scalar_at(hit_record));
+        let (wi, h) = ggx.sample_reflection(wo)?;
+        Some(Scattered {
+            attenuation: self.fresnel(Vec3::dot(wo, h), hit_record) * (ggx.g2(wo, wi) / ggx.g1(wo)),
+            scattered: Ray::at_time(hit_record.point, frame.to_world(wi), ray.time),
+        })
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> Option<Vec3> {
+        let (frame, wo) = Frame::facing(ray, hit_record);
+        let wi = frame.to_local(direction);
+        let ggx = Ggx::from_roughness(self.roughness.scalar_at(hit_record));
+        Some(self.fresnel(Vec3::dot(wo, (wo + wi).normalize()), hit_record) * ggx.reflection(wo, wi))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> f32 {
+        let (frame, wo) = Frame::facing(ray, hit_record);
+        Ggx::from_roughness(self.roughness.scalar_at(hit_record)).reflection_pdf(wo, frame.to_local(direction))
+    }
+}
+
+/// Reflection and refraction on rough microfacets of an interface into a medium with `eta` times
+/// the index of refraction (Walter et al., "Microfacet Models for Refraction through Rough Surfaces").
+#[derive(Copy, Clone)]
+pub(crate) struct DielectricLobe {
+    pub(crate) ggx: Ggx,
+    pub(crate) eta: f32,
+}
+
+impl DielectricLobe {
+    /// Chooses reflection or refraction in proportion to the Fresnel term, together with
+    /// the weight of the direction.
+    pub(crate) fn sample(self, wo: Vec3) -> Option<(Vec3, f32)> {
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let h = self.ggx.sample_visible(wo);
+        let reflectance = fresnel_dielectric(Vec3::dot(wo, h), self.eta);
+
+        let (wi, refracted) = if rng::random::<f32>() < reflectance {
+            (reflect(wo, h), false)
+        } else {
+            (refract(wo, h, self.eta)?, true)
+        };
+        // Directions ending up on the wrong side would scatter again between microfacets.
+        if (wi.z() < 0.0) != refracted || wi.z() == 0.0 {
+            return None;
+        }
+        // Radiance is compressed into a smaller solid angle when entering a denser medium.
+        let scale = if refracted { 1.0 / (self.eta * self.eta) } else { 1.0 };
+        Some((wi, scale * self.ggx.g2(wo, wi) / self.ggx.g1(wo)))
+    }
+
+    /// Microfacet normal turning `wo` into `wi`, on the side of `wo`, and whether it refracts.
+    fn half_vector(self, wo: Vec3, wi: Vec3) -> (Vec3, bool) {
+        let refracted = wi.z() < 0.0;
+        let h = if refracted { wo + self.eta * wi } else { wo + wi };
+        let h = h.normalize();
+        (if h.z() < 0.0 { -h } else { h }, refracted)
+    }
+
+    /// Fraction of light arriving from `wi` scattered towards `wo`, times the cosine at `wi`.
+    pub(crate) fn eval(self, wo: Vec3, wi: Vec3) -> f32 {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return 0.0;
+        }
+        let ggx = self.ggx;
+        let (h, refracted) = self.half_vector(wo, wi);
+        let (cos_o, cos_i) = (Vec3::dot(wo, h), Vec3::dot(wi, h));
+        let reflectance = fresnel_dielectric(cos_o, self.eta);
+
+        if !refracted {
+            if cos_o <= 0.0 {
+                return 0.0;
+            }
+            reflectance * ggx.d(h) * ggx.g2(wo, wi) / (4.0 * wo.z())
+        } else {
+            if cos_o <= 0.0 || cos_i >= 0.0 {
+                return 0.0;
+            }
+            let denominator = cos_o + self.eta * cos_i;
+            (1.0 - reflectance) * ggx.d(h) * ggx.g2(wo, wi) * -cos_i * cos_o
+                / (wo.z() * denominator * denominator)
+        }
+    }
+
+    /// Density of `sample` choosing `wi`.
+    pub(crate) fn pdf(self, wo: Vec3, wi: Vec3) -> f32 {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return 0.0;
+        }
+        let ggx = self.ggx;
+        let (h, refracted) = self.half_vector(wo, wi);
+        let (cos_o, cos_i) = (Vec3::dot(wo, h), Vec3::dot(wi, h));
+        let reflectance = fresnel_dielectric(cos_o, self.eta);
+
+        if !refracted {
+            if cos_o <= 0.0 {
+                return 0.0;
+            }
+            ggx.visible_pdf(wo, h) * reflectance / (4.0 * cos_o)
+        } else {
+            if cos_o <= 0.0 || cos_i >= 0.0 {
+                return 0.0;
+            }
+            let denominator = cos_o + self.eta * cos_i;
+            ggx.visible_pdf(wo, h) * (1.0 - reflectance) * self.eta * self.eta * -cos_i / (denominator * denominator)
+        }
+    }
+}
+
+/// Ratio of indices of refraction across a surface with index `ref_idx`, as seen from the side of the ray.
+pub(crate) fn relative_eta(ref_idx: f32, ray: &Ray, hit_record: &HitRecord) -> f32 {
+    if Vec3::dot(ray.direction, hit_record.normal) < 0.0 {
+        ref_idx
+    } else {
+        1.0 / ref_idx
+    }
+}
+
+/// Rough glass, reflecting and refracting on microfacets.
+pub struct RoughDielectric {
+    pub ref_idx: Arc<dyn Texture>,
+    /// From 0 for a polished surface to 1 for a very rough one.
+    pub roughness: Arc<dyn Texture>,
+}
+
+impl RoughDielectric {
+    fn lobe(&self, ray: &Ray, hit_record: &HitRecord) -> DielectricLobe {
+        DielectricLobe {
+            ggx: Ggx::from_roughness(self.roughness.scalar_at(hit_record)),
+            eta: relative_eta(self.ref_idx.scalar_at(hit_record), ray, hit_record),
+        }
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scattered> {
+        let (frame, wo) = Frame::facing(ray, hit_record);
+        let (wi, weight) = self.lobe(ray, hit_record).sample(wo)?;
+        Some(Scattered {
+            attenuation: Vec3::new(weight, weight, weight),
+            scattered: Ray::at_time(hit_record.point, frame.to_world(wi), ray.time),
+        })
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> Option<Vec3> {
+        let (frame, wo) = Frame::facing(ray, hit_record);
+        let value = self.lobe(ray, hit_record).eval(wo, frame.to_local(direction));
+        Some(Vec3::new(value, value, value))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> f32 {
+        let (frame, wo) = Frame::facing(ray, hit_record);
+        self.lobe(ray, hit_record).pdf(wo, frame.to_local(direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::ConstantTexture;
+
+    fn hit(material: Arc<dyn Material>) -> HitRecord {
+        HitRecord { t: 1.0, point: Vec3::zeros(), normal: Vec3::new(0.0, 0.0, 1.0), u: 0.0, v: 0.0, footprint: 0.0, material }
+    }
+
+    #[test]
+    fn fresnel_matches_known_values() {
+        assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-6);
+        assert_eq!(fresnel_dielectric(0.1, 1.0 / 1.5), 1.0);
+        // A conductor without absorption behaves like a dielectric.
+        for &cos in &[1.0, 0.7, 0.2] {
+            assert!((fresnel_conductor(cos, 1.5, 0.0) - fresnel_dielectric(cos, 1.5)).abs() < 1e-5);
+        }
+        let (eta, k) = (0.143, 3.983);
+        let normal = ((eta - 1.0) * (eta - 1.0) + k * k) / ((eta + 1.0) * (eta + 1.0) + k * k);
+        assert!((fresnel_conductor(1.0, eta, k) - normal).abs() < 1e-5);
+    }
+
+    /// Checks that `scatter` weights its directions by `eval / pdf`, and that `pdf` integrates
+    /// to the fraction of rays that `scatter` does not absorb.
+    fn check_sampling(material: Arc<dyn Material>, incoming: Vec3) {
+        let record = hit(material.clone());
+        let ray = Ray::new(-incoming, incoming);
+
+        let count = 20_000;
+        let mut scattered = 0;
+        for _ in 0..count {
+            if let Some(Scattered { attenuation, scattered: out }) = material.scatter(&ray, &record) {
+                scattered += 1;
+                let direction = out.direction.normalize();
+                let pdf = material.pdf(&ray, &record, direction);
+                let expected = material.eval(&ray, &record, direction).unwrap() / pdf;
+                let error = (attenuation - expected).length();
+                assert!(pdf > 0.0 && error <= 1e-2 * expected.length().max(1e-3), "{:?} {:?}", attenuation, expected);
+            }
+        }
+
+        // Midpoint rule over a grid uniform in solid angle.
+        let (rows, columns) = (400, 400);
+        let mut total = 0.0;
+        for i in 0..rows {
+            let z = -1.0 + 2.0 * (i as f32 + 0.5) / rows as f32;
+            let r = (1.0 - z * z).sqrt();
+            for j in 0..columns {
+                let phi = 2.0 * PI * (j as f32 + 0.5) / columns as f32;
+                let direction = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+                total += material.pdf(&ray, &record, direction) * 4.0 * PI / (rows * columns) as f32;
+            }
+        }
+        let fraction = scattered as f32 / count as f32;
+        assert!((total - fraction).abs() < 0.05, "{} {}", total, fraction);
+    }
+
+    #[test]
+    fn conductor_samples_match_eval_and_pdf() {
+        let gold = Arc::new(RoughConductor::from_name("gold", Arc::new(ConstantTexture::gray(0.7))).unwrap());
+        check_sampling(gold.clone(), Vec3::new(0.0, 0.0, -1.0));
+        check_sampling(gold, Vec3::new(0.8, 0.0, -0.6).normalize());
+    }
+
+    #[test]
+    fn dielectric_samples_match_eval_and_pdf() {
+        let glass = Arc::new(RoughDielectric { ref_idx: Arc::new(ConstantTexture::gray(1.5)), roughness: Arc::new(ConstantTexture::gray(0.7)) });
+        check_sampling(glass.clone(), Vec3::new(0.0, 0.0, -1.0));
+        check_sampling(glass.clone(), Vec3::new(0.6, 0.0, -0.8));
+        // From inside, where total internal reflection takes over at grazing angles.
+        check_sampling(glass, Vec3::new(0.8, 0.0, 0.6));
+    }
+}
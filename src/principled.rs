@@ -0,0 +1,289 @@
+use crate::geometry::Vec3;
+use crate::hitable::HitRecord;
+use crate::material::{Material, Scattered};
+use crate::microfacet::{Frame, Ggx, DielectricLobe, relative_eta};
+use crate::ray::Ray;
+use crate::rng::{self, random_unit_vector};
+use crate::texture::{Texture, ConstantTexture};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Roughness of the clear coat, which is meant to be a glossy varnish.
+const CLEARCOAT_ROUGHNESS: f32 = 0.1;
+
+/// Reflectance of the clear coat at normal incidence, that of a polyurethane varnish.
+const CLEARCOAT_REFLECTANCE: f32 = 0.04;
+
+/// Single material covering most real surfaces, after Burley's "Physically Based Shading
+/// at Disney". A base of metal, glass or a diffuse surface with a specular layer is chosen
+/// by `metallic` and `transmission`, and optionally covered by a clear coat.
+///
+/// All lobes scatter at most the light they receive, with the diffuse lobe getting only
+/// what the specular layers above it do not reflect.
+pub struct Principled {
+    /// Albedo of the diffuse base, reflectance of metals and tint of transmitted light.
+    pub base_color: Arc<dyn Texture>,
+    /// From 0 for dielectrics to 1 for metals.
+    pub metallic: Arc<dyn Texture>,
+    /// From 0 for a polished surface to 1 for a very rough one.
+    pub roughness: Arc<dyn Texture>,
+    /// Strength of the specular reflection of dielectrics, 0.5 being the common 4 percent.
+    pub specular: Arc<dyn Texture>,
+    /// Soft white reflection of fabrics at grazing angles.
+    pub sheen: Arc<dyn Texture>,
+    /// Strength of a glossy varnish on top of the base.
+    pub clearcoat: Arc<dyn Texture>,
+    /// From 0 for an opaque dielectric base to 1 for glass.
+    pub transmission: Arc<dyn Texture>,
+    /// Index of refraction of transmitting materials.
+    pub ref_idx: Arc<dyn Texture>,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Self {
+            base_color: Arc::new(ConstantTexture::gray(0.8)),
+            metallic: Arc::new(ConstantTexture::gray(0.0)),
+            roughness: Arc::new(ConstantTexture::gray(0.5)),
+            specular: Arc::new(ConstantTexture::gray(0.5)),
+            sheen: Arc::new(ConstantTexture::gray(0.0)),
+            clearcoat: Arc::new(ConstantTexture::gray(0.0)),
+            transmission: Arc::new(ConstantTexture::gray(0.0)),
+            ref_idx: Arc::new(ConstantTexture::gray(1.5)),
+        }
+    }
+}
+
+/// Parameters of the material looked up at a hit point.
+struct Parameters {
+    base_color: Vec3,
+    metallic: f32,
+    specular: f32,
+    sheen: f32,
+    clearcoat: f32,
+    transmission: f32,
+}
+
+fn schlick(f0: f32, cosine: f32) -> f32 {
+    f0 + (1.0 - f0) * (1.0 - cosine).max(0.0).powi(5)
+}
+
+/// Probabilities of sampling each lobe, roughly in proportion to the light they scatter.
+struct Lobes {
+    clearcoat: f32,
+    metal: f32,
+    specular: f32,
+    diffuse: f32,
+    glass: f32,
+}
+
+/// Material evaluated in the frame of the surface, with the ray arriving from above.
+struct Local {
+    material: Parameters,
+    wo: Vec3,
+    ggx: Ggx,
+    clearcoat_ggx: Ggx,
+    glass: DielectricLobe,
+    /// Fraction of light passing through the clear coat towards the base.
+    base_weight: f32,
+    /// Reflectance of the specular layer of dielectrics at normal incidence.
+    specular_f0: f32,
+}
+
+impl Local {
+    fn new(principled: &Principled, ray: &Ray, hit_record: &HitRecord) -> (Frame, Local) {
+        let (frame, wo) = Frame::facing(ray, hit_record);
+        let material = Parameters {
+            base_color: principled.base_color.at(hit_record),
+            metallic: principled.metallic.scalar_at(hit_record),
+            specular: principled.specular.scalar_at(hit_record),
+            sheen: principled.sheen.scalar_at(hit_record),
+            clearcoat: principled.clearcoat.scalar_at(hit_record),
+            transmission: principled.transmission.scalar_at(hit_record),
+        };
+        let ggx = Ggx::from_roughness(principled.roughness.scalar_at(hit_record));
+        let local = Local {
+            wo,
+            ggx,
+            clearcoat_ggx: Ggx::from_roughness(CLEARCOAT_ROUGHNESS),
+            glass: DielectricLobe { ggx, eta: relative_eta(principled.ref_idx.scalar_at(hit_record), ray, hit_record) },
+            base_weight: 1.0 - material.clearcoat * schlick(CLEARCOAT_REFLECTANCE, wo.z()),
+            specular_f0: 0.08 * material.specular,
+            material,
+        };
+        (frame, local)
+    }
+
+    fn lobes(&self) -> Lobes {
+        let m = &self.material;
+        let dielectric = self.base_weight * (1.0 - m.metallic);
+        let specular = schlick(self.specular_f0, self.wo.z());
+        let mut lobes = Lobes {
+            clearcoat: m.clearcoat * schlick(CLEARCOAT_REFLECTANCE, self.wo.z()),
+            metal: self.base_weight * m.metallic,
+            specular: dielectric * (1.0 - m.transmission) * specular,
+            diffuse: dielectric * (1.0 - m.transmission) * (1.0 - specular),
+            glass: dielectric * m.transmission,
+        };
+        let total = lobes.clearcoat + lobes.metal + lobes.specular + lobes.diffuse + lobes.glass;
+        if total > 0.0 {
+            lobes.clearcoat /= total;
+            lobes.metal /= total;
+            lobes.specular /= total;
+            lobes.diffuse /= total;
+            lobes.glass /= total;
+        }
+        lobes
+    }
+
+    fn eval(&self, wi: Vec3) -> Vec3 {
+        let m = &self.material;
+        let wo = self.wo;
+        if wo.z() <= 0.0 {
+            return Vec3::zeros();
+        }
+
+        let glass = m.transmission * self.glass.eval(wo, wi);
+        if wi.z() <= 0.0 {
+            // Only glass lets light through, tinted by the base color.
+            return (self.base_weight * (1.0 - m.metallic) * glass) * m.base_color;
+        }
+
+        let h = (wo + wi).normalize();
+        let cos_h = Vec3::dot(wo, h);
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let reflection = self.ggx.reflection(wo, wi);
+
+        let metal = reflection * (m.base_color + (white - m.base_color) * schlick(0.0, cos_h));
+        let specular = schlick(self.specular_f0, cos_h) * reflection;
+        // Sheen replaces part of the diffuse reflection towards grazing angles, keeping its albedo.
+        let grazing = m.sheen * (1.0 - Vec3::dot(wi, h)).max(0.0).powi(5);
+        let diffuse = (wi.z() / PI) * ((1.0 - grazing) * m.base_color + grazing * white);
+        let opaque = specular * white + (1.0 - schlick(self.specular_f0, wo.z())) * diffuse;
+        let base = m.metallic * metal
+            + (1.0 - m.metallic) * ((1.0 - m.transmission) * opaque + glass * white);
+
+        let clearcoat = m.clearcoat * schlick(CLEARCOAT_REFLECTANCE, cos_h) * self.clearcoat_ggx.reflection(wo, wi);
+        clearcoat * white + self.base_weight * base
+    }
+
+    fn pdf(&self, wi: Vec3) -> f32 {
+        let lobes = self.lobes();
+        let wo = self.wo;
+        lobes.clearcoat * self.clearcoat_ggx.reflection_pdf(wo, wi)
+            + (lobes.metal + lobes.specular) * self.ggx.reflection_pdf(wo, wi)
+            + lobes.diffuse * wi.z().max(0.0) / PI
+            + lobes.glass * self.glass.pdf(wo, wi)
+    }
+
+    fn sample(&self) -> Option<Vec3> {
+        let lobes = self.lobes();
+        let choice = rng::random::<f32>();
+        let reflection = lobes.clearcoat + lobes.metal + lobes.specular;
+        let wi = if choice < lobes.clearcoat {
+            self.clearcoat_ggx.sample_reflection(self.wo)?.0
+        } else if choice < reflection {
+            self.ggx.sample_reflection(self.wo)?.0
+        } else if choice < reflection + lobes.diffuse {
+            (Vec3::new(0.0, 0.0, 1.0) + random_unit_vector()).normalize()
+        } else {
+            self.glass.sample(self.wo)?.0
+        };
+        Some(wi)
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<Scattered> {
+        let (frame, local) = Local::new(self, ray, hit_record);
+        let wi = local.sample()?;
+        // Weighted by the density of all lobes together, which could each have chosen the direction.
+        let pdf = local.pdf(wi);
+        if pdf.is_nan() || pdf <= 0.0 || wi.z() == 0.0 {
+            return None;
+        }
+        Some(Scattered {
+            attenuation: local.eval(wi) / pdf,
+            scattered: Ray::at_time(hit_record.point, frame.to_world(wi), ray.time),
+        })
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> Option<Vec3> {
+        let (frame, local) = Local::new(self, ray, hit_record);
+        Some(local.eval(frame.to_local(direction)))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> f32 {
+        let (frame, local) = Local::new(self, ray, hit_record);
+        local.pdf(frame.to_local(direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(value: f32) -> Arc<dyn Texture> {
+        Arc::new(ConstantTexture::gray(value))
+    }
+
+    /// Fraction of light arriving along `incoming` that a white material scatters back out,
+    /// as seen in a uniformly white environment.
+    fn albedo(material: Principled, incoming: Vec3, count: usize) -> Vec3 {
+        let material: Arc<dyn Material> = Arc::new(material);
+        let record = HitRecord { t: 1.0, point: Vec3::zeros(), normal: Vec3::new(0.0, 0.0, 1.0), u: 0.0, v: 0.0, footprint: 0.0, material: material.clone() };
+        let ray = Ray::new(-incoming, incoming);
+        let mut total = Vec3::zeros();
+        for _ in 0..count {
+            if let Some(scattered) = material.scatter(&ray, &record) {
+                total = total + scattered.attenuation;
+            }
+        }
+        total / count as f32
+    }
+
+    #[test]
+    fn white_furnace_does_not_create_energy() {
+        let incoming = [Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.6, 0.0, -0.8), Vec3::new(0.98, 0.0, -0.2).normalize()];
+        for &metallic in &[0.0, 1.0] {
+            for &roughness in &[0.05, 0.5, 1.0] {
+                for &(sheen, clearcoat) in &[(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)] {
+                    for &(specular, transmission) in &[(0.5, 0.0), (1.0, 0.0), (0.5, 1.0)] {
+                        let material = || Principled {
+                            base_color: gray(1.0),
+                            metallic: gray(metallic),
+                            roughness: gray(roughness),
+                            specular: gray(specular),
+                            sheen: gray(sheen),
+                            clearcoat: gray(clearcoat),
+                            transmission: gray(transmission),
+                            ref_idx: gray(1.5),
+                        };
+                        for &direction in &incoming {
+                            let albedo = albedo(material(), direction, 4000);
+                            let max = albedo.x().max(albedo.y()).max(albedo.z());
+                            assert!(
+                                max <= 1.03,
+                                "albedo {} with metallic {}, roughness {}, sheen {}, clearcoat {}, specular {}, transmission {}",
+                                max, metallic, roughness, sheen, clearcoat, specular, transmission,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn white_furnace_keeps_energy_of_smooth_metal_and_diffuse() {
+        let normal = Vec3::new(0.0, 0.0, -1.0);
+        let mirror = albedo(
+            Principled { base_color: gray(1.0), metallic: gray(1.0), roughness: gray(0.0), ..Principled::default() },
+            normal,
+            4000,
+        );
+        assert!(mirror.x() > 0.99, "{:?}", mirror);
+        let diffuse = albedo(Principled { base_color: gray(1.0), specular: gray(0.0), ..Principled::default() }, normal, 20_000);
+        assert!((diffuse.x() - 1.0).abs() < 0.02, "{:?}", diffuse);
+    }
+}
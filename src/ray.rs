@@ -1,19 +1,54 @@
 use crate::geometry::Vec3;
 
-pub(crate) struct Ray {
-    pub(crate) origin: Vec3,
-    pub(crate) direction: Vec3,
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+    /// Moment within the camera shutter interval at which the ray was sent.
+    pub time: f32,
+    /// Area around the ray that it stands for, zero unless set by the renderer.
+    pub cone: RayCone,
+}
+
+/// Beam of light seen through a pixel, growing from `width` at the origin of a ray by `spread`
+/// per unit of distance. Used to blur textures seen from afar instead of aliasing them.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RayCone {
+    pub width: f32,
+    pub spread: f32,
+}
+
+impl RayCone {
+    pub fn width_at(&self, distance: f32) -> f32 {
+        (self.width + self.spread * distance).abs()
+    }
+
+    /// Cone starting at `distance`, as after a reflection on a flat mirror. Rougher surfaces
+    /// spread light more, but textures seen after a bounce need little accuracy.
+    pub fn moved(&self, distance: f32) -> RayCone {
+        RayCone { width: self.width_at(distance), spread: self.spread }
+    }
 }
 
 impl Ray {
-    pub(crate) fn new(origin: Vec3, direction: Vec3) -> Self {
+    pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Self::at_time(origin, direction, 0.0)
+    }
+
+    pub fn at_time(origin: Vec3, direction: Vec3, time: f32) -> Self {
         Self {
             origin,
             direction,
+            time,
+            cone: RayCone::default(),
         }
     }
 
-    pub(crate) fn point_at_parameter(&self, t: f32) -> Vec3 {
+    pub fn point_at_parameter(&self, t: f32) -> Vec3 {
         self.origin + t * self.direction
     }
+
+    /// Width of the cone of the ray at `point_at_parameter(t)`.
+    pub fn width_at_parameter(&self, t: f32) -> f32 {
+        self.cone.width_at(t * self.direction.length())
+    }
 }
\ No newline at end of file
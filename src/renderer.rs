@@ -0,0 +1,297 @@
+use crate::geometry::Vec3;
+use crate::ray::Ray;
+use crate::hitable::HitRecord;
+use crate::light::{Light, LightSample};
+use crate::material::Scattered;
+use crate::scene::{Scene, RenderSettings};
+use crate::image::Image;
+use crate::rng;
+use rayon::prelude::*;
+
+/// Weight of a sample taken with density `pdf` when another strategy could produce it
+/// with density `other_pdf` (Veach's power heuristic with exponent two).
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    if other_pdf.is_infinite() {
+        return 0.0;
+    }
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+/// Lights of the scene split by how they are sampled.
+struct Lights<'a> {
+    area: Vec<&'a dyn Light>,
+    delta: Vec<&'a dyn Light>,
+}
+
+impl<'a> Lights<'a> {
+    fn new(scene: &'a Scene) -> Self {
+        let (delta, area) = scene.lights.iter()
+            .map(|light| light.as_ref())
+            .partition(|light| light.is_delta());
+        Self { area, delta }
+    }
+
+    /// Density of `direct_light` choosing the direction of a ray that missed the world,
+    /// summed over infinite lights.
+    fn pdf_missed(&self, ray: &Ray) -> f32 {
+        if self.area.is_empty() {
+            return 0.0;
+        }
+        let direction = ray.direction.normalize();
+        let total: f32 = self.area.iter()
+            .filter(|light| light.is_infinite())
+            .map(|light| light.pdf(ray.origin, direction, ray.time))
+            .sum();
+        total / self.area.len() as f32
+    }
+
+    /// Density of `direct_light` choosing the direction of a ray that hit an emitter at `t`,
+    /// for the light found there. Lights hidden behind it are left out, because their samples
+    /// in that direction are occluded and get no weight on the light sampling side either.
+    fn pdf_hit(&self, ray: &Ray, t: f32) -> f32 {
+        let hit = self.area.iter()
+            .filter(|light| !light.is_infinite())
+            .find(|light| light.intersect(ray, 0.001).map(|t_light| (t_light - t).abs() <= 1e-4 * t).unwrap_or(false));
+        match hit {
+            Some(light) => light.pdf(ray.origin, ray.direction.normalize(), ray.time) / self.area.len() as f32,
+            None => 0.0,
+        }
+    }
+}
+
+/// Light arriving from the sample, scattered along the ray, unless something is in the way.
+fn unoccluded(ray: &Ray, record: &HitRecord, scene: &Scene, sample: &LightSample) -> Option<Vec3> {
+    if !(sample.pdf > 0.0 && sample.pdf.is_finite()) {
+        return None;
+    }
+    let scattering = record.material.eval(ray, record, sample.direction)?;
+    let shadow_ray = Ray::at_time(record.point, sample.direction, ray.time);
+    if scene.world.hit_any(&shadow_ray, 0.001, sample.distance - 0.001) {
+        return None;
+    }
+    Some(scattering * sample.radiance)
+}
+
+/// Light reaching the hit point directly from all delta lights and one randomly chosen area light,
+/// scattered along the ray. Area lights are weighted against finding them by scattering.
+fn direct_light(ray: &Ray, record: &HitRecord, scene: &Scene, lights: &Lights) -> Vec3 {
+    let mut total = Vec3::zeros();
+    for light in &lights.delta {
+        let sample = light.sample(record.point, ray.time);
+        if let Some(light) = unoccluded(ray, record, scene, &sample) {
+            total = total + light;
+        }
+    }
+
+    if !lights.area.is_empty() {
+        let count = lights.area.len();
+        let index = ((rng::random::<f32>() * count as f32) as usize).min(count - 1);
+        let sample = lights.area[index].sample(record.point, ray.time);
+        if let Some(light) = unoccluded(ray, record, scene, &sample) {
+            let pdf = sample.pdf / count as f32;
+            let weight = power_heuristic(pdf, record.material.pdf(ray, record, sample.direction));
+            total = total + light * (weight / pdf);
+        }
+    }
+    total
+}
+
+/// Radiance arriving along the ray. Paths are terminated with Russian roulette after `min_depth`
+/// bounces, which keeps the estimate unbiased, and always after `max_depth` bounces.
+fn color(ray: Ray, scene: &Scene, lights: &Lights, min_depth: usize, max_depth: usize) -> Vec3 {
+    let mut ray = ray;
+    let mut radiance = Vec3::zeros();
+    let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+    // Density with which the previous bounce chose the direction of the ray, used to weight
+    // emission of lights, which were sampled directly as well. `None` for camera rays
+    // and after specular bounces.
+    let mut scatter_pdf: Option<f32> = None;
+
+    for depth in 0.. {
+        let record = match scene.world.hit(&ray, 0.001, f32::INFINITY) {
+            Some(record) => record,
+            None => {
+                let mut background = scene.environment.radiance(ray.direction.normalize());
+                if let Some(pdf) = scatter_pdf {
+                    background = background * power_heuristic(pdf, lights.pdf_missed(&ray));
+                }
+                radiance = radiance + throughput * background;
+                break;
+            }
+        };
+
+        let mut emitted = record.material.emitted(&ray, &record);
+        if let Some(pdf) = scatter_pdf {
+            if emitted.squared_len() > 0.0 && !lights.area.is_empty() {
+                emitted = emitted * power_heuristic(pdf, lights.pdf_hit(&ray, record.t));
+            }
+        }
+        radiance = radiance + throughput * emitted;
+        if depth >= max_depth {
+            break;
+        }
+
+        radiance = radiance + throughput * direct_light(&ray, &record, scene, lights);
+        let Scattered { attenuation, scattered } = match record.material.scatter(&ray, &record) {
+            Some(scattered) => scattered,
+            None => break,
+        };
+        let pdf = record.material.pdf(&ray, &record, scattered.direction.normalize());
+        scatter_pdf = if pdf > 0.0 { Some(pdf) } else { None };
+        throughput = throughput * attenuation;
+        let cone = ray.cone.moved(record.t * ray.direction.length());
+
+        if depth + 1 >= min_depth {
+            let survival = throughput.x().max(throughput.y()).max(throughput.z()).min(1.0);
+            if rng::random::<f32>() >= survival {
+                break;
+            }
+            throughput = throughput / survival;
+        }
+        ray = Ray { cone, ..scattered };
+    }
+    radiance
+}
+
+/// Renders the scene on the current rayon thread pool.
+pub fn render(scene: &Scene, settings: &RenderSettings) -> Image {
+    let RenderSettings { width, height, samples: ns, min_depth, max_depth, .. } = *settings;
+    let seed = settings.seed.unwrap_or_else(rand::random);
+    let hitables = scene.world.as_ref();
+    let camera = scene.camera.prepare(width, height, hitables);
+    let lights = Lights::new(scene);
+    let cone = camera.ray_cone(1.0 / height as f32);
+
+    let mut frame_buffer = vec![Vec3::new(0.0, 0.0, 0.0); width * height];
+    frame_buffer.par_iter_mut().enumerate().for_each(|(n, pixel)| {
+        rng::seed(seed ^ (n as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+
+        let i = n % width;
+        let j = height - 1 - n / width;
+
+        let mut col = Vec3::new(0.0, 0.0, 0.0);
+        for _ in 0..ns {
+            let u = (i as f32 + rng::random::<f32>()) / width as f32;
+            let v = (j as f32 + rng::random::<f32>()) / height as f32;
+            let r = Ray { cone, ..camera.ray(u, v) };
+            col = col + color(r, scene, &lights, min_depth, max_depth);
+        }
+        *pixel = col / ns as f32;
+    });
+
+    Image::from_pixels(width, height, frame_buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::PerspectiveCamera;
+    use crate::environment::ConstantEnvironment;
+    use crate::hitable::Hitable;
+    use crate::hitable_list::HitableList;
+    use crate::light::{SphereLight, PointLight};
+    use crate::material::{Material, Lambertian, Metal, DiffuseLight};
+    use crate::sphere::Sphere;
+    use crate::texture::ConstantTexture;
+    use std::sync::Arc;
+
+    #[test]
+    fn light_hit_directly_returns_its_emission() {
+        let light = Sphere {
+            center: Vec3::new(0.0, 0.0, -3.0),
+            radius: 1.0,
+            material: Arc::new(DiffuseLight { emit: Arc::new(ConstantTexture::new(Vec3::new(4.0, 2.0, 1.0))) }),
+        };
+        let scene = Scene::new(Box::new(PerspectiveCamera::default()), Box::new(light));
+        let lights = Lights::new(&scene);
+        for &max_depth in &[0, 50] {
+            let ray = Ray::new(Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0));
+            assert_eq!(color(ray, &scene, &lights, max_depth, max_depth).raw, [4.0, 2.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn russian_roulette_keeps_mean() {
+        // Diffuse walls enclosing a lamp, so that paths bounce many times before they reach it.
+        let walls = Sphere {
+            center: Vec3::zeros(),
+            radius: -5.0,
+            material: Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.7)) }),
+        };
+        let lamp = Sphere {
+            center: Vec3::new(0.0, 3.0, 0.0),
+            radius: 1.5,
+            material: Arc::new(DiffuseLight { emit: Arc::new(ConstantTexture::gray(1.0)) }),
+        };
+        let hitables: Vec<Box<dyn Hitable>> = vec![Box::new(walls), Box::new(lamp)];
+        let scene = Scene::new(Box::new(PerspectiveCamera::default()), Box::new(HitableList::from_vec(hitables)));
+        let lights = Lights::new(&scene);
+
+        let mean = |min_depth| {
+            rng::seed(17);
+            let count = 50_000;
+            let mut total = 0.0;
+            for _ in 0..count {
+                let ray = Ray::new(Vec3::zeros(), Vec3::new(1.0, -1.0, 0.0));
+                total += color(ray, &scene, &lights, min_depth, 20).x() / count as f32;
+            }
+            total
+        };
+        let without_roulette = mean(20);
+        let with_roulette = mean(1);
+        assert!(without_roulette > 0.1);
+        assert!((with_roulette - without_roulette).abs() < 0.03 * without_roulette, "{} {}", with_roulette, without_roulette);
+    }
+
+    #[test]
+    fn light_hidden_behind_another_does_not_take_weight() {
+        let lamp: Arc<dyn Material> = Arc::new(DiffuseLight { emit: Arc::new(ConstantTexture::gray(1.0)) });
+        let floor = Sphere {
+            center: Vec3::new(0.0, -1000.0, 0.0),
+            radius: 1000.0,
+            material: Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.5)) }),
+        };
+        // The far light is seen from the origin entirely behind the near one.
+        let near = Sphere { center: Vec3::new(0.0, 1.5, 0.0), radius: 1.0, material: lamp.clone() };
+        let far = Sphere { center: Vec3::new(0.0, 6.0, 0.0), radius: 3.0, material: lamp };
+
+        let lights: Vec<Box<dyn Light>> = vec![Box::new(SphereLight::new(&near)), Box::new(SphereLight::new(&far))];
+        let hitables: Vec<Box<dyn Hitable>> = vec![Box::new(floor), Box::new(near), Box::new(far)];
+        let mut scene = Scene::new(Box::new(PerspectiveCamera::default()), Box::new(HitableList::from_vec(hitables)));
+        scene.environment = Arc::new(ConstantEnvironment { radiance: Vec3::zeros() });
+        scene.lights = lights;
+        let lights = Lights::new(&scene);
+
+        rng::seed(3);
+        let count = 20_000;
+        let mut total = 0.0;
+        for _ in 0..count {
+            let ray = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+            total += color(ray, &scene, &lights, 5, 5).x() / count as f32;
+        }
+
+        // A Lambertian surface under a sphere of unit radiance seen at an angle `a` reflects
+        // albedo * sin²(a), with sin(a) = 1 / 1.5 here.
+        let expected = 0.5 * (1.0 / 1.5f32).powi(2);
+        assert!((total - expected).abs() < 0.01 * expected, "{} {}", total, expected);
+    }
+
+    #[test]
+    fn fuzzy_metal_is_lit_by_point_lights() {
+        let metal = |fuzz: f32| -> Arc<dyn Material> {
+            Arc::new(Metal { albedo: Arc::new(ConstantTexture::gray(0.8)), fuzz: Arc::new(ConstantTexture::gray(fuzz)) })
+        };
+        for &(fuzz, lit) in &[(0.5, true), (0.0, false)] {
+            let floor = Sphere { center: Vec3::new(0.0, -1000.0, 0.0), radius: 1000.0, material: metal(fuzz) };
+            let mut scene = Scene::new(Box::new(PerspectiveCamera::default()), Box::new(floor));
+            scene.lights = vec![Box::new(PointLight { position: Vec3::new(-1.0, 1.5, 0.0), intensity: Vec3::new(1.0, 1.0, 1.0) })];
+            let lights = Lights::new(&scene);
+
+            let ray = Ray::new(Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+            let record = scene.world.hit(&ray, 0.001, f32::INFINITY).unwrap();
+            let light = direct_light(&ray, &record, &scene, &lights);
+            assert_eq!(light.x() > 0.0, lit, "{}", fuzz);
+        }
+    }
+}
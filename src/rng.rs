@@ -0,0 +1,53 @@
+use rand::distributions::{Distribution, Standard};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use crate::geometry::Vec3;
+
+thread_local! {
+    static RNG: RefCell<SmallRng> = RefCell::new(SmallRng::from_entropy());
+}
+
+/// Draws a random value from the generator of the current thread.
+pub(crate) fn random<T>() -> T where Standard: Distribution<T> {
+    RNG.with(|rng| rng.borrow_mut().gen())
+}
+
+/// Reseeds the generator of the current thread. Renderer does this for every pixel,
+/// so that images do not depend on how pixels are distributed between threads.
+pub(crate) fn seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = SmallRng::seed_from_u64(seed))
+}
+
+/// Uniformly distributed direction.
+pub(crate) fn random_unit_vector() -> Vec3 {
+    let z = 1.0 - 2.0 * random::<f32>();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * random::<f32>();
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Uniformly distributed point inside a sphere of radius one.
+pub(crate) fn random_in_unit_sphere() -> Vec3 {
+    loop {
+        let p = Vec3::new(
+            2.0 * random::<f32>() - 1.0,
+            2.0 * random::<f32>() - 1.0,
+            2.0 * random::<f32>() - 1.0,
+        );
+        if p.squared_len() < 1.0 {
+            return p;
+        }
+    }
+}
+
+/// Uniformly distributed point inside a disk of radius one.
+pub(crate) fn random_in_unit_disk() -> (f32, f32) {
+    loop {
+        let x = 2.0 * random::<f32>() - 1.0;
+        let y = 2.0 * random::<f32>() - 1.0;
+        if x * x + y * y < 1.0 {
+            return (x, y);
+        }
+    }
+}
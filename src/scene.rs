@@ -0,0 +1,1130 @@
+use crate::geometry::Vec3;
+use crate::hitable::Hitable;
+use crate::hitable_list::HitableList;
+use crate::bvh::BvhNode;
+use crate::sphere::{Sphere, MovingSphere};
+use crate::instance::MovingInstance;
+use crate::light::{Light, SphereLight, MeshLight, PointLight, SpotLight, DirectionalLight, EnvironmentLight};
+use crate::environment::{Environment, ConstantEnvironment, GradientEnvironment, ImageEnvironment, SunSkyEnvironment};
+use crate::camera::{Camera, PerspectiveCamera, OrthographicCamera, FisheyeCamera, EquirectangularCamera, ShutterCamera};
+use crate::stereo::{StereoCamera, StereoLayout};
+use crate::material::{Material, Lambertian, Metal, Dielectric, DiffuseLight};
+use crate::texture::{Texture, ConstantTexture, CheckerTexture, GradientTexture, ImageTexture, TextureFilter, WrapMode};
+use crate::microfacet::{RoughConductor, RoughDielectric};
+use crate::principled::Principled;
+use crate::triangulated_model::TriangulatedModel;
+use crate::mesh_utils::load_obj;
+use crate::image::Image;
+use serde::Deserialize;
+use toml::Spanned;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::fmt;
+
+pub struct Scene {
+    pub camera: Box<dyn Camera>,
+    pub world: Box<dyn Hitable>,
+    /// Radiance of rays that miss the world, the sky gradient unless set otherwise.
+    pub environment: Arc<dyn Environment>,
+    /// Emissive objects of the world that are sampled directly, in addition to being found
+    /// by scattered rays. Emissive objects missing here still light the scene, only with more noise.
+    pub lights: Vec<Box<dyn Light>>,
+}
+
+impl Scene {
+    pub fn new(camera: Box<dyn Camera>, world: Box<dyn Hitable>) -> Self {
+        Self {
+            camera,
+            world,
+            environment: Arc::new(GradientEnvironment::default()),
+            lights: vec![],
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    /// Number of bounces after which paths are terminated with Russian roulette.
+    pub min_depth: usize,
+    pub max_depth: usize,
+    pub output: PathBuf,
+    /// Seed of the random number generator, random when not given.
+    pub seed: Option<u64>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            samples: 100,
+            min_depth: 3,
+            max_depth: 50,
+            output: PathBuf::from("image.ppm"),
+            seed: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(PathBuf, std::io::Error),
+    Syntax(toml::de::Error),
+    Invalid { line: usize, message: String },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            SceneError::Syntax(e) => write!(f, "{}", e),
+            SceneError::Invalid { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    environment: Option<EnvironmentDesc>,
+    #[serde(default)]
+    render: RenderDesc,
+    #[serde(default)]
+    camera: CameraDesc,
+    #[serde(default)]
+    textures: HashMap<String, TextureDesc>,
+    #[serde(default)]
+    materials: HashMap<String, MaterialDesc>,
+    #[serde(default)]
+    spheres: Vec<SphereDesc>,
+    #[serde(default)]
+    meshes: Vec<MeshDesc>,
+    #[serde(default)]
+    lights: Vec<LightDesc>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RenderDesc {
+    width: Option<Spanned<usize>>,
+    height: Option<Spanned<usize>>,
+    samples: Option<Spanned<usize>>,
+    min_depth: Option<Spanned<usize>>,
+    max_depth: Option<usize>,
+    output: Option<PathBuf>,
+    seed: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct CameraDesc {
+    #[serde(rename = "type")]
+    kind: Option<Spanned<String>>,
+    look_from: Option<Spanned<[f32; 3]>>,
+    look_at: Option<Spanned<[f32; 3]>>,
+    up: Option<Spanned<[f32; 3]>>,
+    vfov: Option<Spanned<f32>>,
+    aspect: Option<Spanned<f32>>,
+    aperture: Option<Spanned<f32>>,
+    focus_distance: Option<Spanned<f32>>,
+    autofocus: bool,
+    height: Option<Spanned<f32>>,
+    fov: Option<Spanned<f32>>,
+    shutter: Option<Spanned<[f32; 2]>>,
+    stereo: Option<StereoDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StereoDesc {
+    interocular: Spanned<f32>,
+    convergence: Option<Spanned<f32>>,
+    layout: Option<Spanned<String>>,
+}
+
+/// Parameters of all material types are kept in one table, so that errors
+/// can point at the exact line of the offending field.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    albedo: Option<Spanned<ParamDesc<[f32; 3]>>>,
+    ref_idx: Option<Spanned<ParamDesc<f32>>>,
+    emit: Option<Spanned<ParamDesc<[f32; 3]>>>,
+    fuzz: Option<Spanned<ParamDesc<f32>>>,
+    roughness: Option<Spanned<ParamDesc<f32>>>,
+    preset: Option<Spanned<String>>,
+    eta: Option<Spanned<ParamDesc<[f32; 3]>>>,
+    k: Option<Spanned<ParamDesc<[f32; 3]>>>,
+    base_color: Option<Spanned<ParamDesc<[f32; 3]>>>,
+    metallic: Option<Spanned<ParamDesc<f32>>>,
+    specular: Option<Spanned<ParamDesc<f32>>>,
+    sheen: Option<Spanned<ParamDesc<f32>>>,
+    clearcoat: Option<Spanned<ParamDesc<f32>>>,
+    transmission: Option<Spanned<ParamDesc<f32>>>,
+}
+
+/// Material parameter given either directly, as a color or a number, or by the name of a texture.
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a value or the name of a texture")]
+enum ParamDesc<T> {
+    Value(T),
+    Texture(String),
+}
+
+/// Textures that material parameters refer to by name: a 3D "checker" of cubes of `size`
+/// alternating between `even` and `odd`, a "gradient" from `start` to `end` along `v`, or an
+/// "image" loaded from `path`, decoded from sRGB unless `srgb` is false, and looked up with
+/// a `filter` and a `wrap` mode. Checkers can be made of other textures defined above them.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextureDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    even: Option<Spanned<ParamDesc<[f32; 3]>>>,
+    odd: Option<Spanned<ParamDesc<[f32; 3]>>>,
+    size: Option<Spanned<f32>>,
+    start: Option<Spanned<[f32; 3]>>,
+    end: Option<Spanned<[f32; 3]>>,
+    path: Option<Spanned<PathBuf>>,
+    srgb: Option<Spanned<bool>>,
+    filter: Option<Spanned<String>>,
+    wrap: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SphereDesc {
+    center: [f32; 3],
+    radius: Spanned<f32>,
+    material: Spanned<String>,
+    motion: Option<[f32; 3]>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshDesc {
+    path: Spanned<PathBuf>,
+    material: Spanned<String>,
+    #[serde(default)]
+    smooth_normals: bool,
+    #[serde(default)]
+    translate: [f32; 3],
+    scale: Option<Spanned<f32>>,
+    motion: Option<[f32; 3]>,
+}
+
+/// Point, spot and directional lights. For directional lights `intensity` is the irradiance.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    position: Option<Spanned<[f32; 3]>>,
+    direction: Option<Spanned<[f32; 3]>>,
+    intensity: Spanned<[f32; 3]>,
+    inner_angle: Option<Spanned<f32>>,
+    outer_angle: Option<Spanned<f32>>,
+}
+
+/// What rays missing the world see: a "constant" `color`, a "gradient" from `bottom` to `top`,
+/// an equirectangular "image" loaded from a Radiance `.hdr` file at `path`, or a daylight "sky"
+/// lit by the sun in `sun_direction`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    color: Option<Spanned<[f32; 3]>>,
+    bottom: Option<Spanned<[f32; 3]>>,
+    top: Option<Spanned<[f32; 3]>>,
+    path: Option<Spanned<PathBuf>>,
+    rotation: Option<Spanned<f32>>,
+    intensity: Option<Spanned<f32>>,
+    sun_direction: Option<Spanned<[f32; 3]>>,
+    turbidity: Option<Spanned<f32>>,
+    ground_albedo: Option<Spanned<[f32; 3]>>,
+}
+
+fn vec3([x, y, z]: [f32; 3]) -> Vec3 {
+    Vec3::new(x, y, z)
+}
+
+impl Scene {
+    /// Loads the scene and its render settings from a TOML file.
+    /// Paths of meshes are resolved relative to the scene file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<(Scene, RenderSettings), SceneError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| SceneError::Io(path.to_path_buf(), e))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Scene::parse(&source, base_dir)
+    }
+
+    pub fn parse(source: &str, base_dir: &Path) -> Result<(Scene, RenderSettings), SceneError> {
+        let desc: SceneDesc = toml::from_str(source).map_err(SceneError::Syntax)?;
+        let invalid = |offset: usize, message: String| SceneError::Invalid {
+            line: line_of(source, offset),
+            message,
+        };
+
+        let positive = |value: Option<Spanned<usize>>, default: usize, name: &str| match value {
+            Some(value) if *value.get_ref() == 0 => Err(invalid(value.start(), format!("{} must be positive", name))),
+            Some(value) => Ok(value.into_inner()),
+            None => Ok(default),
+        };
+
+        let render = desc.render;
+        let defaults = RenderSettings::default();
+        let settings = RenderSettings {
+            width: positive(render.width, defaults.width, "width")?,
+            height: positive(render.height, defaults.height, "height")?,
+            samples: positive(render.samples, defaults.samples, "samples")?,
+            min_depth: defaults.min_depth,
+            max_depth: render.max_depth.unwrap_or(defaults.max_depth),
+            output: render.output.unwrap_or(defaults.output),
+            seed: render.seed,
+        };
+        // Russian roulette would never start, so only the default follows a lower `max_depth`.
+        let settings = match render.min_depth {
+            Some(depth) if *depth.get_ref() > settings.max_depth => {
+                return Err(invalid(depth.start(), "min_depth must not be greater than max_depth".to_string()));
+            }
+            Some(depth) => RenderSettings { min_depth: depth.into_inner(), ..settings },
+            None => RenderSettings { min_depth: settings.min_depth.min(settings.max_depth), ..settings },
+        };
+
+        let camera = parse_camera(&desc.camera, source)?;
+
+        let textures = parse_textures(desc.textures, source, base_dir)?;
+        let find_texture = |name: &str, start: usize| textures.get(name)
+            .cloned()
+            .ok_or_else(|| invalid(start, format!("undefined texture `{}`", name)));
+        let color = |param: &Spanned<ParamDesc<[f32; 3]>>, valid: fn(f32) -> bool, message: &str| match param.get_ref() {
+            ParamDesc::Value(color) if !color.iter().all(|c| valid(*c)) => Err(invalid(param.start(), message.to_string())),
+            ParamDesc::Value(color) => Ok(Arc::new(ConstantTexture::new(vec3(*color))) as Arc<dyn Texture>),
+            ParamDesc::Texture(name) => find_texture(name, param.start()),
+        };
+        let scalar = |param: &Spanned<ParamDesc<f32>>, valid: fn(f32) -> bool, message: &str| match param.get_ref() {
+            ParamDesc::Value(value) if !valid(*value) => Err(invalid(param.start(), message.to_string())),
+            ParamDesc::Value(value) => Ok(Arc::new(ConstantTexture::gray(*value)) as Arc<dyn Texture>),
+            ParamDesc::Texture(name) => find_texture(name, param.start()),
+        };
+        let unit = |value: f32| (0.0..=1.0).contains(&value);
+
+        // Validate in the order of appearance, so that the first error in the file is reported
+        let mut material_descs: Vec<_> = desc.materials.into_iter().collect();
+        material_descs.sort_by_key(|(_, material)| material.kind.start());
+
+        // Materials are stored together with whether they emit light.
+        let mut materials: HashMap<String, (Arc<dyn Material>, bool)> = HashMap::new();
+        for (name, material) in material_descs {
+            let kind = material.kind.get_ref().as_str();
+            let supported: &[&str] = match kind {
+                "lambertian" => &["albedo"],
+                "metal" => &["albedo", "fuzz"],
+                "dielectric" => &["ref_idx"],
+                "conductor" => &["preset", "eta", "k", "roughness"],
+                "rough_dielectric" => &["ref_idx", "roughness"],
+                "principled" => &[
+                    "base_color", "metallic", "roughness", "specular", "sheen", "clearcoat", "transmission", "ref_idx",
+                ],
+                "diffuse_light" => &["emit"],
+                other => return Err(invalid(
+                    material.kind.start(),
+                    format!(
+                        "unknown material type `{}`, expected one of `lambertian`, `metal`, `dielectric`, \
+                         `conductor`, `rough_dielectric`, `principled`, `diffuse_light`",
+                        other,
+                    ),
+                )),
+            };
+            let fields = [
+                ("albedo", material.albedo.as_ref().map(|v| v.start())),
+                ("ref_idx", material.ref_idx.as_ref().map(|v| v.start())),
+                ("emit", material.emit.as_ref().map(|v| v.start())),
+                ("fuzz", material.fuzz.as_ref().map(|v| v.start())),
+                ("roughness", material.roughness.as_ref().map(|v| v.start())),
+                ("preset", material.preset.as_ref().map(|v| v.start())),
+                ("eta", material.eta.as_ref().map(|v| v.start())),
+                ("k", material.k.as_ref().map(|v| v.start())),
+                ("base_color", material.base_color.as_ref().map(|v| v.start())),
+                ("metallic", material.metallic.as_ref().map(|v| v.start())),
+                ("specular", material.specular.as_ref().map(|v| v.start())),
+                ("sheen", material.sheen.as_ref().map(|v| v.start())),
+                ("clearcoat", material.clearcoat.as_ref().map(|v| v.start())),
+                ("transmission", material.transmission.as_ref().map(|v| v.start())),
+            ];
+            for (field, start) in fields.iter() {
+                if let Some(start) = start {
+                    if !supported.contains(field) {
+                        return Err(invalid(*start, format!("`{}` is not supported by {} material", field, kind)));
+                    }
+                }
+            }
+
+            let require_albedo = || {
+                let albedo = material.albedo.as_ref()
+                    .ok_or_else(|| invalid(material.kind.start(), format!("material `{}` requires `albedo`", name)))?;
+                color(albedo, |c| c >= 0.0, "albedo must not be negative")
+            };
+
+            let require_ref_idx = || {
+                let ref_idx = material.ref_idx.as_ref()
+                    .ok_or_else(|| invalid(material.kind.start(), format!("material `{}` requires `ref_idx`", name)))?;
+                scalar(ref_idx, |value| value > 0.0, "ref_idx must be positive")
+            };
+            let factor = |value: &Option<Spanned<ParamDesc<f32>>>, name: &str, default: f32| match value {
+                Some(value) => scalar(value, unit, &format!("{} must be between 0 and 1", name)),
+                None => Ok(Arc::new(ConstantTexture::gray(default)) as Arc<dyn Texture>),
+            };
+            let roughness = || factor(&material.roughness, "roughness", 0.5);
+
+            let (built, emissive): (Arc<dyn Material>, bool) = match kind {
+                "lambertian" => (Arc::new(Lambertian { albedo: require_albedo()? }), false),
+                "metal" => (Arc::new(Metal { albedo: require_albedo()?, fuzz: factor(&material.fuzz, "fuzz", 0.0)? }), false),
+                "dielectric" => (Arc::new(Dielectric { ref_idx: require_ref_idx()? }), false),
+                "conductor" => {
+                    let roughness = roughness()?;
+                    let conductor: Arc<dyn Material> = match (&material.preset, &material.eta, &material.k) {
+                        (Some(preset), None, None) => Arc::new(
+                            RoughConductor::from_name(preset.get_ref(), roughness).ok_or_else(|| invalid(
+                                preset.start(),
+                                format!(
+                                    "unknown conductor `{}`, expected one of `gold`, `copper`, `aluminium`, `silver`",
+                                    preset.get_ref(),
+                                ),
+                            ))?
+                        ),
+                        (None, Some(eta), Some(k)) => Arc::new(RoughConductor {
+                            eta: color(eta, |c| c >= 0.0, "eta must not be negative")?,
+                            k: color(k, |c| c >= 0.0, "k must not be negative")?,
+                            roughness,
+                        }),
+                        _ => return Err(invalid(
+                            material.kind.start(),
+                            format!("material `{}` requires either `preset` or both `eta` and `k`", name),
+                        )),
+                    };
+                    (conductor, false)
+                }
+                "rough_dielectric" => (Arc::new(RoughDielectric { ref_idx: require_ref_idx()?, roughness: roughness()? }), false),
+                "principled" => {
+                    let defaults = Principled::default();
+                    let base_color = match &material.base_color {
+                        Some(base_color) => color(base_color, unit, "base_color must be between 0 and 1")?,
+                        None => defaults.base_color,
+                    };
+                    let ref_idx = match material.ref_idx {
+                        Some(_) => require_ref_idx()?,
+                        None => defaults.ref_idx,
+                    };
+                    (Arc::new(Principled {
+                        base_color,
+                        metallic: factor(&material.metallic, "metallic", 0.0)?,
+                        roughness: roughness()?,
+                        specular: factor(&material.specular, "specular", 0.5)?,
+                        sheen: factor(&material.sheen, "sheen", 0.0)?,
+                        clearcoat: factor(&material.clearcoat, "clearcoat", 0.0)?,
+                        transmission: factor(&material.transmission, "transmission", 0.0)?,
+                        ref_idx,
+                    }), false)
+                }
+                _ => {
+                    let emit = material.emit.as_ref()
+                        .ok_or_else(|| invalid(material.kind.start(), format!("material `{}` requires `emit`", name)))?;
+                    (Arc::new(DiffuseLight { emit: color(emit, |c| c >= 0.0, "emit must not be negative")? }), true)
+                }
+            };
+            materials.insert(name, (built, emissive));
+        }
+
+        let find_material = |name: &Spanned<String>| materials.get(name.get_ref())
+            .cloned()
+            .ok_or_else(|| invalid(name.start(), format!("undefined material `{}`", name.get_ref())));
+
+        let mut hitables: Vec<Box<dyn Hitable>> = vec![];
+        let mut lights: Vec<Box<dyn Light>> = vec![];
+        for sphere in desc.spheres {
+            if *sphere.radius.get_ref() == 0.0 {
+                return Err(invalid(sphere.radius.start(), "radius must be non-zero".to_string()));
+            }
+            let (material, emissive) = find_material(&sphere.material)?;
+            let center = vec3(sphere.center);
+            let radius = sphere.radius.into_inner();
+            match sphere.motion {
+                Some(motion) => {
+                    let sphere = MovingSphere {
+                        center0: center,
+                        center1: center + vec3(motion),
+                        time0: 0.0,
+                        time1: 1.0,
+                        radius,
+                        material,
+                    };
+                    if emissive {
+                        lights.push(Box::new(SphereLight::moving(&sphere)));
+                    }
+                    hitables.push(Box::new(sphere));
+                }
+                None => {
+                    let sphere = Sphere { center, radius, material };
+                    if emissive {
+                        lights.push(Box::new(SphereLight::new(&sphere)));
+                    }
+                    hitables.push(Box::new(sphere));
+                }
+            }
+        }
+
+        for mesh in desc.meshes {
+            let (material, emissive) = find_material(&mesh.material)?;
+            if emissive && mesh.motion.is_some() {
+                return Err(invalid(mesh.material.start(), "moving meshes cannot emit light".to_string()));
+            }
+            let scale = match &mesh.scale {
+                Some(scale) if *scale.get_ref() <= 0.0 => {
+                    return Err(invalid(scale.start(), "scale must be positive".to_string()));
+                }
+                Some(scale) => *scale.get_ref(),
+                None => 1.0,
+            };
+
+            let path = base_dir.join(mesh.path.get_ref());
+            let loaded = load_obj(&path, mesh.smooth_normals)
+                .map_err(|e| invalid(mesh.path.start(), format!("cannot load mesh `{}`: {}", path.display(), e)))?;
+            let model = TriangulatedModel::new(
+                loaded.transformed(scale, vec3(mesh.translate)),
+                material,
+            );
+            if emissive {
+                lights.push(Box::new(MeshLight::new(model.mesh.clone(), model.material.clone())));
+            }
+            match mesh.motion {
+                Some(motion) => hitables.push(Box::new(
+                    MovingInstance::new(Box::new(model), Vec3::zeros(), vec3(motion), 0.0, 1.0)
+                )),
+                None => hitables.push(Box::new(model)),
+            }
+        }
+
+        for light in desc.lights {
+            let kind = light.kind.get_ref().as_str();
+            let supported: &[&str] = match kind {
+                "point" => &["position"],
+                "spot" => &["position", "direction", "inner_angle", "outer_angle"],
+                "directional" => &["direction"],
+                other => return Err(invalid(
+                    light.kind.start(),
+                    format!("unknown light type `{}`, expected one of `point`, `spot`, `directional`", other),
+                )),
+            };
+            let fields = [
+                ("position", light.position.as_ref().map(|v| v.start())),
+                ("direction", light.direction.as_ref().map(|v| v.start())),
+                ("inner_angle", light.inner_angle.as_ref().map(|v| v.start())),
+                ("outer_angle", light.outer_angle.as_ref().map(|v| v.start())),
+            ];
+            for (name, start) in fields.iter() {
+                if let Some(start) = start {
+                    if !supported.contains(name) {
+                        return Err(invalid(*start, format!("`{}` is not supported by {} light", name, kind)));
+                    }
+                }
+            }
+
+            let intensity = &light.intensity;
+            if intensity.get_ref().iter().any(|c| c.is_nan() || *c < 0.0) {
+                return Err(invalid(intensity.start(), "intensity must not be negative".to_string()));
+            }
+            let intensity = vec3(*intensity.get_ref());
+            let require_position = || light.position.as_ref().map(|position| vec3(*position.get_ref()))
+                .ok_or_else(|| invalid(light.kind.start(), format!("{} light requires `position`", kind)));
+            let require_direction = || match &light.direction {
+                Some(direction) if vec3(*direction.get_ref()).squared_len() == 0.0 => {
+                    Err(invalid(direction.start(), "direction must not be zero".to_string()))
+                }
+                Some(direction) => Ok(vec3(*direction.get_ref())),
+                None => Err(invalid(light.kind.start(), format!("{} light requires `direction`", kind))),
+            };
+            let angle = |angle: &Option<Spanned<f32>>, default: f32| match angle {
+                Some(angle) if !(*angle.get_ref() >= 0.0 && *angle.get_ref() <= 180.0) => {
+                    Err(invalid(angle.start(), "angles must be between 0 and 180 degrees".to_string()))
+                }
+                Some(angle) => Ok(*angle.get_ref()),
+                None => Ok(default),
+            };
+
+            match kind {
+                "point" => lights.push(Box::new(PointLight { position: require_position()?, intensity })),
+                "spot" => {
+                    let inner_angle = angle(&light.inner_angle, 30.0)?;
+                    let outer_angle = angle(&light.outer_angle, inner_angle)?;
+                    if outer_angle < inner_angle {
+                        let line = light.outer_angle.as_ref().map_or(light.kind.start(), |angle| angle.start());
+                        return Err(invalid(line, "outer_angle must not be smaller than inner_angle".to_string()));
+                    }
+                    lights.push(Box::new(SpotLight::new(
+                        require_position()?,
+                        require_direction()?,
+                        intensity,
+                        inner_angle,
+                        outer_angle,
+                    )));
+                }
+                _ => lights.push(Box::new(DirectionalLight::new(require_direction()?, intensity))),
+            }
+        }
+
+        let environment = match &desc.environment {
+            Some(environment) => {
+                let (environment, sampled) = parse_environment(environment, source, base_dir)?;
+                if sampled {
+                    lights.push(Box::new(EnvironmentLight::new(environment.clone())));
+                }
+                Some(environment)
+            }
+            None => None,
+        };
+
+        let world = BvhNode::from_list(HitableList::from_vec(hitables));
+        let mut scene = Scene::new(camera, Box::new(world));
+        if let Some(environment) = environment {
+            scene.environment = environment;
+        }
+        scene.lights = lights;
+        Ok((scene, settings))
+    }
+}
+
+/// Builds the environment, together with whether it is worth sampling directly.
+fn parse_environment(
+    environment: &EnvironmentDesc,
+    source: &str,
+    base_dir: &Path,
+) -> Result<(Arc<dyn Environment>, bool), SceneError> {
+    let invalid = |offset: usize, message: String| SceneError::Invalid {
+        line: line_of(source, offset),
+        message,
+    };
+    let kind = environment.kind.get_ref().as_str();
+
+    // Reject parameters that the chosen environment would silently ignore.
+    let supported: &[&str] = match kind {
+        "constant" => &["color"],
+        "gradient" => &["bottom", "top"],
+        "image" => &["path", "rotation", "intensity"],
+        "sky" => &["sun_direction", "turbidity", "ground_albedo", "intensity"],
+        other => return Err(invalid(
+            environment.kind.start(),
+            format!("unknown environment type `{}`, expected one of `constant`, `gradient`, `image`, `sky`", other),
+        )),
+    };
+    let fields = [
+        ("color", environment.color.as_ref().map(|v| v.start())),
+        ("bottom", environment.bottom.as_ref().map(|v| v.start())),
+        ("top", environment.top.as_ref().map(|v| v.start())),
+        ("path", environment.path.as_ref().map(|v| v.start())),
+        ("rotation", environment.rotation.as_ref().map(|v| v.start())),
+        ("intensity", environment.intensity.as_ref().map(|v| v.start())),
+        ("sun_direction", environment.sun_direction.as_ref().map(|v| v.start())),
+        ("turbidity", environment.turbidity.as_ref().map(|v| v.start())),
+        ("ground_albedo", environment.ground_albedo.as_ref().map(|v| v.start())),
+    ];
+    for (name, start) in fields.iter() {
+        if let Some(start) = start {
+            if !supported.contains(name) {
+                return Err(invalid(*start, format!("`{}` is not supported by {} environment", name, kind)));
+            }
+        }
+    }
+
+    let color = |color: &Option<Spanned<[f32; 3]>>, default: Vec3| match color {
+        Some(color) if color.get_ref().iter().any(|c| c.is_nan() || *c < 0.0) => {
+            Err(invalid(color.start(), "environment colors must not be negative".to_string()))
+        }
+        Some(color) => Ok(vec3(*color.get_ref())),
+        None => Ok(default),
+    };
+
+    let intensity = match &environment.intensity {
+        Some(intensity) if intensity.get_ref().is_nan() || *intensity.get_ref() < 0.0 => {
+            return Err(invalid(intensity.start(), "intensity must not be negative".to_string()));
+        }
+        Some(intensity) => *intensity.get_ref(),
+        None => 1.0,
+    };
+
+    Ok(match kind {
+        "constant" => (Arc::new(ConstantEnvironment { radiance: color(&environment.color, Vec3::zeros())? }), false),
+        "gradient" => {
+            let default = GradientEnvironment::default();
+            let gradient = GradientEnvironment {
+                bottom: color(&environment.bottom, default.bottom)?,
+                top: color(&environment.top, default.top)?,
+            };
+            (Arc::new(gradient), false)
+        }
+        "sky" => {
+            let sun_direction = match &environment.sun_direction {
+                Some(direction) if vec3(*direction.get_ref()).squared_len() == 0.0 => {
+                    return Err(invalid(direction.start(), "sun_direction must not be zero".to_string()));
+                }
+                Some(direction) => vec3(*direction.get_ref()),
+                None => Vec3::new(0.0, 1.0, 0.0),
+            };
+            let turbidity = match &environment.turbidity {
+                Some(turbidity) if !(*turbidity.get_ref() >= 2.0 && *turbidity.get_ref() <= 10.0) => {
+                    return Err(invalid(turbidity.start(), "turbidity must be between 2 and 10".to_string()));
+                }
+                Some(turbidity) => *turbidity.get_ref(),
+                None => 3.0,
+            };
+            let ground_albedo = match &environment.ground_albedo {
+                Some(albedo) if albedo.get_ref().iter().any(|c| !(*c >= 0.0 && *c <= 1.0)) => {
+                    return Err(invalid(albedo.start(), "ground_albedo must be between 0 and 1".to_string()));
+                }
+                Some(albedo) => vec3(*albedo.get_ref()),
+                None => Vec3::new(0.2, 0.2, 0.2),
+            };
+            (Arc::new(SunSkyEnvironment::new(sun_direction, turbidity, ground_albedo, intensity)), true)
+        }
+        _ => {
+            let path = environment.path.as_ref()
+                .ok_or_else(|| invalid(environment.kind.start(), "image environment requires `path`".to_string()))?;
+            let rotation = match &environment.rotation {
+                Some(rotation) if !rotation.get_ref().is_finite() => {
+                    return Err(invalid(rotation.start(), "rotation must be finite".to_string()));
+                }
+                Some(rotation) => rotation.get_ref().to_radians(),
+                None => 0.0,
+            };
+            let full_path = base_dir.join(path.get_ref());
+            let image = Image::load_hdr(&full_path)
+                .map_err(|e| invalid(path.start(), format!("cannot load environment `{}`: {}", full_path.display(), e)))?;
+            (Arc::new(ImageEnvironment::new(image, rotation, intensity)), true)
+        }
+    })
+}
+
+fn parse_textures(
+    descs: HashMap<String, TextureDesc>,
+    source: &str,
+    base_dir: &Path,
+) -> Result<HashMap<String, Arc<dyn Texture>>, SceneError> {
+    let invalid = |offset: usize, message: String| SceneError::Invalid {
+        line: line_of(source, offset),
+        message,
+    };
+
+    // In the order of appearance, so that checkers can use textures defined above them.
+    let mut descs: Vec<_> = descs.into_iter().collect();
+    descs.sort_by_key(|(_, texture)| texture.kind.start());
+
+    let mut textures: HashMap<String, Arc<dyn Texture>> = HashMap::new();
+    for (name, texture) in &descs {
+        let kind = texture.kind.get_ref().as_str();
+
+        // Reject parameters that the chosen texture would silently ignore.
+        let supported: &[&str] = match kind {
+            "checker" => &["even", "odd", "size"],
+            "gradient" => &["start", "end"],
+            "image" => &["path", "srgb", "filter", "wrap"],
+            other => return Err(invalid(
+                texture.kind.start(),
+                format!("unknown texture type `{}`, expected one of `checker`, `gradient`, `image`", other),
+            )),
+        };
+        let fields = [
+            ("even", texture.even.as_ref().map(|v| v.start())),
+            ("odd", texture.odd.as_ref().map(|v| v.start())),
+            ("size", texture.size.as_ref().map(|v| v.start())),
+            ("start", texture.start.as_ref().map(|v| v.start())),
+            ("end", texture.end.as_ref().map(|v| v.start())),
+            ("path", texture.path.as_ref().map(|v| v.start())),
+            ("srgb", texture.srgb.as_ref().map(|v| v.start())),
+            ("filter", texture.filter.as_ref().map(|v| v.start())),
+            ("wrap", texture.wrap.as_ref().map(|v| v.start())),
+        ];
+        for (field, start) in fields.iter() {
+            if let Some(start) = start {
+                if !supported.contains(field) {
+                    return Err(invalid(*start, format!("`{}` is not supported by {} texture", field, kind)));
+                }
+            }
+        }
+
+        let negative = |color: &[f32; 3]| color.iter().any(|c| c.is_nan() || *c < 0.0);
+        let color = |param: &Option<Spanned<[f32; 3]>>, default: Vec3| match param {
+            Some(color) if negative(color.get_ref()) => {
+                Err(invalid(color.start(), "texture colors must not be negative".to_string()))
+            }
+            Some(color) => Ok(vec3(*color.get_ref())),
+            None => Ok(default),
+        };
+        let built: Arc<dyn Texture> = match kind {
+            "checker" => {
+                let cell = |param: &Option<Spanned<ParamDesc<[f32; 3]>>>, default: f32| match param {
+                    Some(param) => match param.get_ref() {
+                        ParamDesc::Value(color) if negative(color) => {
+                            Err(invalid(param.start(), "texture colors must not be negative".to_string()))
+                        }
+                        ParamDesc::Value(color) => Ok(Arc::new(ConstantTexture::new(vec3(*color))) as Arc<dyn Texture>),
+                        ParamDesc::Texture(other) => textures.get(other).cloned().ok_or_else(|| {
+                            let message = if descs.iter().any(|(defined, _)| defined == other) {
+                                format!("texture `{}` must be defined before `{}`", other, name)
+                            } else {
+                                format!("undefined texture `{}`", other)
+                            };
+                            invalid(param.start(), message)
+                        }),
+                    },
+                    None => Ok(Arc::new(ConstantTexture::gray(default)) as Arc<dyn Texture>),
+                };
+                let size = match &texture.size {
+                    Some(size) if size.get_ref().is_nan() || *size.get_ref() <= 0.0 => {
+                        return Err(invalid(size.start(), "size must be positive".to_string()));
+                    }
+                    Some(size) => *size.get_ref(),
+                    None => 1.0,
+                };
+                Arc::new(CheckerTexture { even: cell(&texture.even, 1.0)?, odd: cell(&texture.odd, 0.0)?, size })
+            }
+            "gradient" => Arc::new(GradientTexture {
+                start: color(&texture.start, Vec3::zeros())?,
+                end: color(&texture.end, Vec3::new(1.0, 1.0, 1.0))?,
+            }),
+            _ => {
+                let path = texture.path.as_ref()
+                    .ok_or_else(|| invalid(texture.kind.start(), format!("texture `{}` requires `path`", name)))?;
+                let filter = match &texture.filter {
+                    Some(filter) => TextureFilter::from_name(filter.get_ref()).ok_or_else(|| invalid(
+                        filter.start(),
+                        format!("unknown filter `{}`, expected one of `nearest`, `bilinear`, `trilinear`", filter.get_ref()),
+                    ))?,
+                    None => TextureFilter::Trilinear,
+                };
+                let wrap = match &texture.wrap {
+                    Some(wrap) => WrapMode::from_name(wrap.get_ref()).ok_or_else(|| invalid(
+                        wrap.start(),
+                        format!("unknown wrap mode `{}`, expected one of `repeat`, `clamp`, `mirror`", wrap.get_ref()),
+                    ))?,
+                    None => WrapMode::Repeat,
+                };
+                let srgb = texture.srgb.as_ref().map(|srgb| *srgb.get_ref()).unwrap_or(true);
+                let full_path = base_dir.join(path.get_ref());
+                let image = Image::load(&full_path, srgb)
+                    .map_err(|e| invalid(path.start(), format!("cannot load texture `{}`: {}", full_path.display(), e)))?;
+                Arc::new(ImageTexture::new(image, filter, wrap))
+            }
+        };
+        textures.insert(name.clone(), built);
+    }
+    Ok(textures)
+}
+
+fn parse_camera(camera: &CameraDesc, source: &str) -> Result<Box<dyn Camera>, SceneError> {
+    let invalid = |offset: usize, message: String| SceneError::Invalid {
+        line: line_of(source, offset),
+        message,
+    };
+
+    let look_from = camera.look_from.as_ref().map_or(Vec3::zeros(), |v| vec3(*v.get_ref()));
+    let look_at = camera.look_at.as_ref().map_or(Vec3::new(0.0, 0.0, -1.0), |v| vec3(*v.get_ref()));
+    let up = camera.up.as_ref().map_or(Vec3::new(0.0, 1.0, 0.0), |v| vec3(*v.get_ref()));
+    let camera_line = camera.look_at.as_ref().or(camera.look_from.as_ref()).map_or(0, |v| v.start());
+    if (look_at - look_from).squared_len() == 0.0 {
+        return Err(invalid(camera_line, "look_from and look_at must differ".to_string()));
+    }
+    if Vec3::cross(up, look_at - look_from).squared_len() == 0.0 {
+        let line = camera.up.as_ref().map_or(camera_line, |v| v.start());
+        return Err(invalid(line, "up must not be parallel to the viewing direction".to_string()));
+    }
+
+    let kind = camera.kind.as_ref().map_or("perspective", |kind| kind.get_ref().as_str());
+
+    // Reject parameters that the chosen projection would silently ignore.
+    let supported: &[&str] = match kind {
+        "perspective" => &["vfov", "aspect", "aperture", "focus_distance"],
+        "orthographic" => &["height", "aspect"],
+        "fisheye" => &["fov"],
+        "equirectangular" => &[],
+        other => return Err(invalid(
+            camera.kind.as_ref().map_or(0, |kind| kind.start()),
+            format!("unknown camera type `{}`", other),
+        )),
+    };
+    let fields = [
+        ("vfov", camera.vfov.as_ref()),
+        ("aspect", camera.aspect.as_ref()),
+        ("aperture", camera.aperture.as_ref()),
+        ("focus_distance", camera.focus_distance.as_ref()),
+        ("height", camera.height.as_ref()),
+        ("fov", camera.fov.as_ref()),
+    ];
+    for (name, value) in fields.iter() {
+        if let Some(value) = value {
+            if !supported.contains(name) {
+                return Err(invalid(value.start(), format!("`{}` is not supported by {} camera", name, kind)));
+            }
+        }
+    }
+    if camera.autofocus && kind != "perspective" {
+        return Err(invalid(camera_line, format!("`autofocus` is not supported by {} camera", kind)));
+    }
+
+    let aspect = match &camera.aspect {
+        Some(aspect) if *aspect.get_ref() <= 0.0 => {
+            return Err(invalid(aspect.start(), "aspect must be positive".to_string()));
+        }
+        Some(aspect) => Some(*aspect.get_ref()),
+        None => None,
+    };
+
+    let lens: Box<dyn Camera> = match kind {
+        "perspective" => {
+            let vfov = match &camera.vfov {
+                Some(vfov) if !(*vfov.get_ref() > 0.0 && *vfov.get_ref() < 180.0) => {
+                    return Err(invalid(vfov.start(), "vfov must be between 0 and 180 degrees".to_string()));
+                }
+                Some(vfov) => *vfov.get_ref(),
+                None => 90.0,
+            };
+            let aperture = match &camera.aperture {
+                Some(aperture) if aperture.get_ref().is_nan() || *aperture.get_ref() < 0.0 => {
+                    return Err(invalid(aperture.start(), "aperture must not be negative".to_string()));
+                }
+                Some(aperture) => *aperture.get_ref(),
+                None => 0.0,
+            };
+            let lens = PerspectiveCamera::look_at(look_from, look_at, up, vfov, aspect);
+            Box::new(match &camera.focus_distance {
+                Some(distance) if camera.autofocus => {
+                    return Err(invalid(distance.start(), "focus_distance cannot be used with autofocus".to_string()));
+                }
+                Some(distance) if distance.get_ref().is_nan() || *distance.get_ref() <= 0.0 => {
+                    return Err(invalid(distance.start(), "focus_distance must be positive".to_string()));
+                }
+                Some(distance) => lens.with_lens(aperture, *distance.get_ref()),
+                None if camera.autofocus => lens.with_autofocus(aperture),
+                None => lens.with_lens(aperture, (look_at - look_from).length()),
+            })
+        }
+        "orthographic" => {
+            let height = match &camera.height {
+                Some(height) if height.get_ref().is_nan() || *height.get_ref() <= 0.0 => {
+                    return Err(invalid(height.start(), "height must be positive".to_string()));
+                }
+                Some(height) => *height.get_ref(),
+                None => 2.0,
+            };
+            Box::new(OrthographicCamera::look_at(look_from, look_at, up, height, aspect))
+        }
+        "fisheye" => {
+            let fov = match &camera.fov {
+                Some(fov) if !(*fov.get_ref() > 0.0 && *fov.get_ref() <= 360.0) => {
+                    return Err(invalid(fov.start(), "fov must be between 0 and 360 degrees".to_string()));
+                }
+                Some(fov) => *fov.get_ref(),
+                None => 180.0,
+            };
+            Box::new(FisheyeCamera::look_at(look_from, look_at, up, fov))
+        }
+        _ => Box::new(EquirectangularCamera::look_at(look_from, look_at, up)),
+    };
+
+    let lens: Box<dyn Camera> = match &camera.shutter {
+        Some(shutter) => {
+            let [open, close] = *shutter.get_ref();
+            if open.is_nan() || close.is_nan() || open > close {
+                return Err(invalid(shutter.start(), "shutter must not close before it opens".to_string()));
+            }
+            Box::new(ShutterCamera::new(lens, open, close))
+        }
+        None => lens,
+    };
+
+    let stereo = match &camera.stereo {
+        Some(stereo) => stereo,
+        None => return Ok(lens),
+    };
+    let interocular = &stereo.interocular;
+    if interocular.get_ref().is_nan() || *interocular.get_ref() < 0.0 {
+        return Err(invalid(interocular.start(), "interocular must not be negative".to_string()));
+    }
+    let convergence = match &stereo.convergence {
+        Some(convergence) if convergence.get_ref().is_nan() || *convergence.get_ref() <= 0.0 => {
+            return Err(invalid(convergence.start(), "convergence must be positive".to_string()));
+        }
+        Some(convergence) => *convergence.get_ref(),
+        None => f32::INFINITY,
+    };
+    let layout = match &stereo.layout {
+        Some(layout) => StereoLayout::from_name(layout.get_ref()).ok_or_else(|| invalid(
+            layout.start(),
+            format!("unknown stereo layout `{}`, expected `side-by-side` or `over-under`", layout.get_ref()),
+        ))?,
+        None => StereoLayout::SideBySide,
+    };
+    let rig = StereoCamera::new(lens, *interocular.get_ref(), convergence, layout)
+        .ok_or_else(|| invalid(interocular.start(), "stereo rigs do not nest".to_string()))?;
+    Ok(Box::new(rig))
+}
+
+/// Converts a byte offset into a one-based line number.
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_line(source: &str) -> usize {
+        match Scene::parse(source, Path::new("")) {
+            Err(SceneError::Invalid { line, .. }) => line,
+            Err(SceneError::Syntax(e)) => e.line_col().unwrap().0 + 1,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("scene should be rejected"),
+        }
+    }
+
+    #[test]
+    fn parses_scene_with_defaults() {
+        let (scene, settings) = Scene::parse(r#"
+            [materials.red]
+            type = "lambertian"
+            albedo = [0.8, 0.1, 0.1]
+
+            [[spheres]]
+            center = [0.0, 0.0, -1.0]
+            radius = 0.5
+            material = "red"
+        "#, Path::new("")).unwrap();
+
+        assert_eq!(settings.width, 1920);
+        assert_eq!(settings.height, 1080);
+        assert_eq!(settings.min_depth, 3);
+        assert_eq!(settings.max_depth, 50);
+        assert!(!scene.world.bounding_box().is_empty());
+    }
+
+    #[test]
+    fn default_min_depth_follows_lower_max_depth() {
+        let (_, settings) = Scene::parse("[render]\nmax_depth = 2\n", Path::new("")).unwrap();
+        assert_eq!(settings.min_depth, 2);
+        assert_eq!(settings.max_depth, 2);
+    }
+
+    #[test]
+    fn reports_line_of_undefined_material() {
+        let source = "[materials.red]\ntype = \"lambertian\"\nalbedo = [1.0, 0.0, 0.0]\n\n[[spheres]]\ncenter = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"blue\"\n";
+        assert_eq!(error_line(source), 8);
+    }
+
+    #[test]
+    fn reports_line_of_invalid_values() {
+        assert_eq!(error_line("[render]\nwidth = 640\nheight = 0\n"), 3);
+        assert_eq!(error_line("[render]\nmax_depth = 4\nmin_depth = 5\n"), 3);
+        assert_eq!(error_line("[render]\nmin_depth = 60\n"), 2);
+        assert_eq!(error_line("[materials.glass]\ntype = \"dielectric\"\nref_idx = -1.0\n"), 3);
+        assert_eq!(error_line("[camera]\naperture = 0.1\nfocus_distance = 0.0\n"), 3);
+        assert_eq!(error_line("[camera]\nautofocus = true\nfocus_distance = 2.0\n"), 3);
+        assert_eq!(error_line("[camera]\ntype = \"pinhole\"\n"), 2);
+        assert_eq!(error_line("[camera]\ntype = \"orthographic\"\nvfov = 60.0\n"), 3);
+        assert_eq!(error_line("[materials.lamp]\ntype = \"diffuse_light\"\nemit = [4.0, -1.0, 4.0]\n"), 3);
+        assert_eq!(error_line("[[lights]]\ntype = \"spot\"\nposition = [0.0, 1.0, 0.0]\ndirection = [0.0, 0.0, 0.0]\nintensity = [1.0, 1.0, 1.0]\n"), 4);
+        assert_eq!(error_line("[[lights]]\ntype = \"area\"\nintensity = [1.0, 1.0, 1.0]\n"), 2);
+        assert_eq!(error_line("[[lights]]\ntype = \"directional\"\ndirection = [0.0, -1.0, 0.0]\nposition = [0.0, 1.0, 0.0]\nintensity = [1.0, 1.0, 1.0]\n"), 4);
+        assert_eq!(error_line("[[lights]]\ntype = \"point\"\nposition = [0.0, 1.0, 0.0]\nintensity = [1.0, 1.0, 1.0]\nouter_angle = 45.0\n"), 5);
+        assert_eq!(error_line("[[lights]]\ntype = \"point\"\nintensity = [1.0, 1.0, 1.0]\ndirection = [0.0, -1.0, 0.0]\n"), 4);
+        assert_eq!(error_line("[camera]\nshutter = [1.0, 0.5]\n"), 2);
+        assert_eq!(error_line("[camera.stereo]\ninterocular = 0.064\nlayout = \"top-bottom\"\n"), 3);
+        assert_eq!(error_line("\n[materials.x]\ntype = \"plastic\"\n"), 3);
+        assert_eq!(error_line("[materials.x]\ntype = \"metal\"\n"), 2);
+        assert_eq!(error_line("[materials.x]\ntype = \"metal\"\nalbedo = [1.0, 1.0, 1.0]\nfuzz = 1.5\n"), 4);
+        assert_eq!(error_line("[materials.x]\ntype = \"conductor\"\npreset = \"brass\"\n"), 3);
+        assert_eq!(error_line("[materials.x]\ntype = \"conductor\"\neta = [1.0, 1.0, 1.0]\n"), 2);
+        assert_eq!(error_line("[materials.x]\ntype = \"rough_dielectric\"\nref_idx = 1.5\nroughness = -0.1\n"), 4);
+        assert_eq!(error_line("[materials.x]\ntype = \"principled\"\nmetallic = 0.5\nsheen = 2.0\n"), 4);
+        assert_eq!(error_line("[materials.x]\ntype = \"principled\"\nbase_color = [0.5, 1.5, 0.5]\n"), 3);
+        assert_eq!(error_line("[materials.x]\ntype = \"lambertian\"\nalbedo = [0.5, 0.5, 0.5]\nfuzz = 0.1\n"), 4);
+        assert_eq!(error_line("[materials.x]\ntype = \"lambertian\"\nalbedo = [-1.0, 0.0, 0.0]\n"), 3);
+        assert_eq!(error_line("[materials.x]\ntype = \"metal\"\nalbedo = [0.5, nan, 0.5]\n"), 3);
+        assert_eq!(error_line("[materials.x]\ntype = \"dielectric\"\nref_idx = 1.5\nroughness = 0.2\n"), 4);
+        assert_eq!(error_line("[materials.x]\ntype = \"principled\"\nalbedo = [0.5, 0.5, 0.5]\n"), 3);
+        assert_eq!(error_line("[materials.x]\ntype = \"diffuse_light\"\nemit = [1.0, 1.0, 1.0]\nalbedo = [0.5, 0.5, 0.5]\n"), 4);
+        assert_eq!(error_line("[environment]\ntype = \"constant\"\ncolor = [0.0, -1.0, 0.0]\n"), 3);
+        assert_eq!(error_line("[environment]\ntype = \"gradient\"\nintensity = 2.0\n"), 3);
+        assert_eq!(error_line("[environment]\ntype = \"image\"\n"), 2);
+        assert_eq!(error_line("[environment]\ntype = \"sky\"\nturbidity = 1.0\n"), 3);
+        assert_eq!(error_line("[environment]\ntype = \"sky\"\nrotation = 90.0\n"), 3);
+        assert_eq!(error_line("[environment]\ntype = \"image\"\npath = \"does-not-exist.hdr\"\n"), 3);
+    }
+
+    #[test]
+    fn resolves_textures_of_material_parameters() {
+        let (scene, _) = Scene::parse(r#"
+            [textures.tiles]
+            type = "checker"
+            even = [0.9, 0.9, 0.9]
+            odd = [0.1, 0.1, 0.1]
+            size = 0.5
+
+            [textures.floor]
+            type = "checker"
+            even = "tiles"
+            odd = [0.5, 0.0, 0.0]
+
+            [materials.ground]
+            type = "principled"
+            base_color = "floor"
+            roughness = "tiles"
+
+            [materials.glass]
+            type = "rough_dielectric"
+            ref_idx = "tiles"
+
+            [materials.copper]
+            type = "conductor"
+            eta = "floor"
+            k = "tiles"
+
+            [[spheres]]
+            center = [0.0, -1000.0, 0.0]
+            radius = 1000.0
+            material = "ground"
+        "#, Path::new("")).unwrap();
+        assert!(!scene.world.bounding_box().is_empty());
+
+        assert_eq!(error_line("[materials.x]\ntype = \"lambertian\"\nalbedo = \"wood\"\n"), 3);
+        assert_eq!(error_line("[materials.x]\ntype = \"dielectric\"\nref_idx = \"wood\"\n"), 3);
+        assert_eq!(error_line("[materials.x]\ntype = \"conductor\"\neta = [1.0, 1.0, 1.0]\nk = [1.0, -1.0, 1.0]\n"), 4);
+        assert_eq!(error_line("[textures.x]\ntype = \"checker\"\neven = \"y\"\n\n[textures.y]\ntype = \"gradient\"\n"), 3);
+        assert_eq!(error_line("[textures.x]\ntype = \"gradient\"\nsize = 1.0\n"), 3);
+        assert_eq!(error_line("[textures.x]\ntype = \"marble\"\n"), 2);
+        assert_eq!(error_line("[textures.x]\ntype = \"gradient\"\nstart = [0.0, 0.0, 0.0]\nend = [1.0, -1.0, 1.0]\n"), 4);
+        assert_eq!(error_line("[textures.x]\ntype = \"gradient\"\nstart = [nan, 0.0, 0.0]\n"), 3);
+        assert_eq!(error_line("[textures.x]\ntype = \"checker\"\nodd = [-0.1, 0.0, 0.0]\n"), 3);
+        assert_eq!(error_line("[textures.x]\ntype = \"image\"\npath = \"wood.png\"\nwrap = \"tile\"\n"), 4);
+        assert_eq!(error_line("[textures.x]\ntype = \"image\"\npath = \"wood.tga\"\n"), 3);
+    }
+
+    #[test]
+    fn reports_line_of_syntax_errors() {
+        assert_eq!(error_line("[render]\nwidth = 640\nheight = \n"), 3);
+    }
+
+    #[test]
+    fn reports_line_of_missing_mesh() {
+        let source = "[materials.x]\ntype = \"metal\"\nalbedo = [1.0, 1.0, 1.0]\n\n[[meshes]]\npath = \"does-not-exist.obj\"\nmaterial = \"x\"\n";
+        assert_eq!(error_line(source), 6);
+    }
+}
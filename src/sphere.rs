@@ -2,44 +2,208 @@ use crate::hitable::{Hitable, HitRecord};
 use crate::ray::Ray;
 use crate::geometry::Vec3;
 use crate::material::Material;
+use crate::aabb::Aabb;
+use std::f32::consts::PI;
 use std::sync::Arc;
 
-pub(crate) struct Sphere {
-    pub(crate) center: Vec3,
-    pub(crate) radius: f32,
-    pub(crate) material: Arc<dyn Material>,
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+    pub material: Arc<dyn Material>,
 }
 
 impl Hitable for Sphere {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let oc = ray.origin - self.center;
-        let a = Vec3::dot(ray.direction, ray.direction);
-        let b = Vec3::dot(oc, ray.direction);
-        let c = Vec3::dot(oc, oc) - self.radius * self.radius;
-        let discriminant = b * b - a * c;
-        if discriminant > 0.0 {
-            let t = (-b - (b * b - a * c).sqrt()) / a;
-            if t < t_max && t > t_min {
-                let p = ray.point_at_parameter(t);
-                return Some(HitRecord {
-                    t,
-                    point: p,
-                    normal: (p - self.center) / self.radius,
-                    material: self.material.clone(),
-                });
-            }
-
-            let t = (-b + (b * b - a * c).sqrt()) / a;
-            if t < t_max && t > t_min {
-                let p = ray.point_at_parameter(t);
-                return Some(HitRecord {
-                    t,
-                    point: p,
-                    normal: (p - self.center) / self.radius,
-                    material: self.material.clone(),
-                });
-            }
+        hit_sphere(self.center, self.radius, &self.material, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        sphere_box(self.center, self.radius)
+    }
+}
+
+/// Sphere moving linearly from `center0` at `time0` to `center1` at `time1`.
+/// It stays in place before and after that interval.
+pub struct MovingSphere {
+    pub center0: Vec3,
+    pub center1: Vec3,
+    pub time0: f32,
+    pub time1: f32,
+    pub radius: f32,
+    pub material: Arc<dyn Material>,
+}
+
+impl MovingSphere {
+    pub fn center(&self, time: f32) -> Vec3 {
+        if self.time1 <= self.time0 {
+            return self.center0;
+        }
+        let s = ((time - self.time0) / (self.time1 - self.time0)).clamp(0.0, 1.0);
+        self.center0 + s * (self.center1 - self.center0)
+    }
+}
+
+impl Hitable for MovingSphere {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        hit_sphere(self.center(ray.time), self.radius, &self.material, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::union(sphere_box(self.center0, self.radius), sphere_box(self.center1, self.radius))
+    }
+}
+
+fn hit_sphere(
+    center: Vec3,
+    radius: f32,
+    material: &Arc<dyn Material>,
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<HitRecord> {
+    let oc = ray.origin - center;
+    let a = Vec3::dot(ray.direction, ray.direction);
+    let b = Vec3::dot(oc, ray.direction);
+    let c = Vec3::dot(oc, oc) - radius * radius;
+    let discriminant = b * b - a * c;
+    if discriminant > 0.0 {
+        let t = (-b - (b * b - a * c).sqrt()) / a;
+        if t < t_max && t > t_min {
+            let p = ray.point_at_parameter(t);
+            let (u, v) = sphere_uv((p - center) / radius.abs());
+            return Some(HitRecord {
+                t,
+                point: p,
+                normal: (p - center) / radius,
+                u,
+                v,
+                footprint: sphere_footprint(ray, t, radius),
+                material: material.clone(),
+            });
         }
-        None
+
+        let t = (-b + (b * b - a * c).sqrt()) / a;
+        if t < t_max && t > t_min {
+            let p = ray.point_at_parameter(t);
+            let (u, v) = sphere_uv((p - center) / radius.abs());
+            return Some(HitRecord {
+                t,
+                point: p,
+                normal: (p - center) / radius,
+                u,
+                v,
+                footprint: sphere_footprint(ray, t, radius),
+                material: material.clone(),
+            });
+        }
+    }
+    None
+}
+
+/// Texture coordinates of the point in `direction` from the center of a sphere, with `u` going
+/// around the y axis starting from -x and `v` from the bottom pole to the top one.
+pub(crate) fn sphere_uv(direction: Vec3) -> (f32, f32) {
+    let theta = (-direction.y()).clamp(-1.0, 1.0).acos();
+    let phi = (-direction.z()).atan2(direction.x()) + PI;
+    (phi / (2.0 * PI), theta / PI)
+}
+
+/// Width of the cone of the ray in texture coordinates, measured along `v`, which unlike `u`
+/// keeps its scale towards the poles.
+fn sphere_footprint(ray: &Ray, t: f32, radius: f32) -> f32 {
+    ray.width_at_parameter(t) / (PI * radius.abs())
+}
+
+fn sphere_box(center: Vec3, radius: f32) -> Aabb {
+    // Negative radius is used for hollow spheres, so the extent has to ignore the sign.
+    let r = radius.abs();
+    Aabb::new(
+        center - Vec3::new(r, r, r),
+        center + Vec3::new(r, r, r),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::texture::ConstantTexture;
+
+    fn assert_uv(direction: Vec3, expected: (f32, f32)) {
+        let (u, v) = sphere_uv(direction);
+        assert!((u - expected.0).abs() < 1e-3 && (v - expected.1).abs() < 1e-3, "{:?} {:?}", (u, v), expected);
+    }
+
+    #[test]
+    fn uv_of_poles_and_axes() {
+        assert_uv(Vec3::new(1.0, 0.0, 0.0), (0.5, 0.5));
+        assert_uv(Vec3::new(0.0, 0.0, 1.0), (0.25, 0.5));
+        assert_uv(Vec3::new(0.0, 0.0, -1.0), (0.75, 0.5));
+        assert_eq!(sphere_uv(Vec3::new(0.0, -1.0, 0.0)).1, 0.0);
+        assert_eq!(sphere_uv(Vec3::new(0.0, 1.0, 0.0)).1, 1.0);
+    }
+
+    #[test]
+    fn u_wraps_around_at_seam() {
+        // The seam lies at -x, where u jumps from one back to zero.
+        assert_uv(Vec3::new(-1.0, 0.0, 0.01).normalize(), (0.0016, 0.5));
+        assert_uv(Vec3::new(-1.0, 0.0, -0.01).normalize(), (0.9984, 0.5));
+        let (u, _) = sphere_uv(Vec3::new(-1.0, 0.0, 0.0));
+        assert!(u == 0.0 || u == 1.0);
+    }
+
+    #[test]
+    fn hit_reports_uv_of_hit_point() {
+        let sphere = Sphere {
+            center: Vec3::new(0.0, 0.0, -3.0),
+            radius: 2.0,
+            material: Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.5)) }),
+        };
+        // Hits the side of the sphere facing +z, and from inside of a hollow one the same point.
+        let record = sphere.hit(&Ray::new(Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0)), 0.001, f32::INFINITY).unwrap();
+        assert!((record.u - 0.25).abs() < 1e-4 && (record.v - 0.5).abs() < 1e-4);
+        let hollow = Sphere { radius: -2.0, ..sphere };
+        let record = hollow.hit(&Ray::new(Vec3::new(0.0, 0.0, -3.0), Vec3::new(0.0, 0.0, 1.0)), 0.001, f32::INFINITY).unwrap();
+        assert!((record.u - 0.25).abs() < 1e-4 && (record.v - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn moving_sphere_is_hit_at_center_of_ray_time() {
+        let sphere = MovingSphere {
+            center0: Vec3::new(0.0, 0.0, -5.0),
+            center1: Vec3::new(4.0, 0.0, -5.0),
+            time0: 0.0,
+            time1: 1.0,
+            radius: 1.0,
+            material: Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.5)) }),
+        };
+        let towards = |x: f32| Vec3::new(x, 0.0, -5.0);
+        let hit = |x, time| sphere.hit(&Ray::at_time(Vec3::zeros(), towards(x), time), 0.001, f32::INFINITY);
+
+        let record = hit(2.0, 0.5).unwrap();
+        assert!(((record.point - towards(2.0)).length() - 1.0).abs() < 1e-4);
+        assert!(Vec3::dot(record.normal, Vec3::new(-2.0, 0.0, 5.0).normalize()) > 0.999);
+        assert!(hit(0.0, 0.5).is_none());
+        assert!(hit(4.0, 0.5).is_none());
+        assert!(hit(0.0, 0.0).is_some());
+        assert!(hit(4.0, 1.0).is_some());
+        // Outside of the interval the sphere stays at the nearest end.
+        assert!(hit(0.0, -1.0).is_some());
+        assert!(hit(4.0, 2.0).is_some());
+    }
+
+    #[test]
+    fn moving_sphere_box_covers_both_ends() {
+        let sphere = MovingSphere {
+            center0: Vec3::new(-1.0, 2.0, 0.0),
+            center1: Vec3::new(3.0, -2.0, 1.0),
+            time0: 0.0,
+            time1: 1.0,
+            radius: 0.5,
+            material: Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.5)) }),
+        };
+        let bounds = sphere.bounding_box();
+        assert!((bounds.min() - Vec3::new(-1.5, -2.5, -0.5)).length() < 1e-6);
+        assert!((bounds.max() - Vec3::new(3.5, 2.5, 1.5)).length() < 1e-6);
     }
 }
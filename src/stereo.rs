@@ -0,0 +1,197 @@
+use crate::camera::Camera;
+use crate::hitable::Hitable;
+use crate::ray::{Ray, RayCone};
+
+/// How images of both eyes are packed into a single frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StereoLayout {
+    /// Left eye in the left half of the image.
+    SideBySide,
+    /// Left eye in the upper half of the image.
+    OverUnder,
+}
+
+impl StereoLayout {
+    pub fn from_name(name: &str) -> Option<StereoLayout> {
+        match name {
+            "side-by-side" => Some(StereoLayout::SideBySide),
+            "over-under" => Some(StereoLayout::OverUnder),
+            _ => None,
+        }
+    }
+
+    /// Size of the image of a single eye within a frame of the given size.
+    fn eye_size(self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            StereoLayout::SideBySide => ((width / 2).max(1), height),
+            StereoLayout::OverUnder => (width, (height / 2).max(1)),
+        }
+    }
+}
+
+/// Renders the view of `camera` for two eyes `interocular` units apart. With a panoramic camera
+/// this produces omni-directional stereo.
+pub struct StereoCamera {
+    camera: Box<dyn Camera>,
+    interocular: f32,
+    convergence: f32,
+    layout: StereoLayout,
+}
+
+impl StereoCamera {
+    /// Views of both eyes meet at `convergence` distance, pass `f32::INFINITY` for parallel eyes.
+    /// Returns `None` when `camera` is a stereo rig itself, as rigs do not nest.
+    pub fn new(camera: Box<dyn Camera>, interocular: f32, convergence: f32, layout: StereoLayout) -> Option<Self> {
+        if camera.is_stereo() {
+            return None;
+        }
+        Some(Self {
+            camera,
+            interocular,
+            convergence,
+            layout,
+        })
+    }
+}
+
+impl Camera for StereoCamera {
+    /// Eyes only exist once the size of the image is known, see `prepare`.
+    fn ray(&self, _u: f32, _v: f32) -> Ray {
+        panic!("stereo camera must be prepared before tracing rays");
+    }
+
+    fn prepare(&self, width: usize, height: usize, world: &dyn Hitable) -> Box<dyn Camera> {
+        // The eyes are derived from the prepared camera, so that they share its focus.
+        let (eye_width, eye_height) = self.layout.eye_size(width, height);
+        let camera = self.camera.prepare(eye_width, eye_height, world);
+        Box::new(StereoPair {
+            left: camera.eye(-self.interocular / 2.0, self.convergence),
+            right: camera.eye(self.interocular / 2.0, self.convergence),
+            layout: self.layout,
+        })
+    }
+
+    fn eye(&self, _offset: f32, _convergence: f32) -> Box<dyn Camera> {
+        panic!("stereo rigs do not nest");
+    }
+
+    fn is_stereo(&self) -> bool {
+        true
+    }
+
+    fn ray_cone(&self, pixel: f32) -> RayCone {
+        self.camera.ray_cone(pixel)
+    }
+}
+
+/// Prepared cameras of both eyes.
+struct StereoPair {
+    left: Box<dyn Camera>,
+    right: Box<dyn Camera>,
+    layout: StereoLayout,
+}
+
+impl Camera for StereoPair {
+    fn ray(&self, u: f32, v: f32) -> Ray {
+        match self.layout {
+            StereoLayout::SideBySide if u < 0.5 => self.left.ray(2.0 * u, v),
+            StereoLayout::SideBySide => self.right.ray(2.0 * u - 1.0, v),
+            StereoLayout::OverUnder if v >= 0.5 => self.left.ray(u, 2.0 * v - 1.0),
+            StereoLayout::OverUnder => self.right.ray(u, 2.0 * v),
+        }
+    }
+
+    fn prepare(&self, width: usize, height: usize, world: &dyn Hitable) -> Box<dyn Camera> {
+        let (eye_width, eye_height) = self.layout.eye_size(width, height);
+        Box::new(StereoPair {
+            left: self.left.prepare(eye_width, eye_height, world),
+            right: self.right.prepare(eye_width, eye_height, world),
+            layout: self.layout,
+        })
+    }
+
+    fn eye(&self, _offset: f32, _convergence: f32) -> Box<dyn Camera> {
+        panic!("stereo rigs do not nest");
+    }
+
+    fn is_stereo(&self) -> bool {
+        true
+    }
+
+    fn ray_cone(&self, pixel: f32) -> RayCone {
+        match self.layout {
+            StereoLayout::SideBySide => self.left.ray_cone(pixel),
+            StereoLayout::OverUnder => self.left.ray_cone(2.0 * pixel),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{PerspectiveCamera, EquirectangularCamera, ShutterCamera};
+    use crate::geometry::Vec3;
+    use crate::hitable_list::HitableList;
+
+    fn point_at(ray: &Ray, z: f32) -> Vec3 {
+        let t = (z - ray.origin.z()) / ray.direction.z();
+        ray.origin + t * ray.direction
+    }
+
+    #[test]
+    fn eyes_converge_at_convergence_distance() {
+        let camera = PerspectiveCamera::look_at(
+            Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 60.0, None,
+        );
+        let stereo = StereoCamera::new(Box::new(camera), 0.2, 3.0, StereoLayout::SideBySide)
+            .unwrap()
+            .prepare(200, 100, &HitableList::from_vec(vec![]));
+
+        for &(u, v) in &[(0.5, 0.5), (0.1, 0.8), (0.9, 0.2)] {
+            let left = stereo.ray(u / 2.0, v);
+            let right = stereo.ray(0.5 + u / 2.0, v);
+            assert!((left.origin.x() + 0.1).abs() < 1e-6);
+            assert!((right.origin.x() - 0.1).abs() < 1e-6);
+            let meet = point_at(&left, -3.0) - point_at(&right, -3.0);
+            assert!(meet.length() < 1e-5, "{:?}", meet);
+        }
+    }
+
+    #[test]
+    fn omnidirectional_eyes_are_on_a_circle() {
+        let camera = EquirectangularCamera::look_at(
+            Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0),
+        );
+        let stereo = StereoCamera::new(Box::new(camera), 0.2, f32::INFINITY, StereoLayout::OverUnder)
+            .unwrap()
+            .prepare(200, 200, &HitableList::from_vec(vec![]));
+
+        for &u in &[0.0, 0.3, 0.5, 0.8] {
+            for &v in &[0.75, 0.25] {
+                let ray = stereo.ray(u, v);
+                assert!((ray.origin.length() - 0.1).abs() < 1e-6);
+                assert!(Vec3::dot(ray.origin, ray.direction).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn stereo_rigs_do_not_nest() {
+        let camera = || Box::new(EquirectangularCamera::look_at(
+            Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0),
+        ));
+        let stereo = || Box::new(StereoCamera::new(camera(), 0.2, f32::INFINITY, StereoLayout::SideBySide).unwrap());
+        assert!(StereoCamera::new(stereo(), 0.2, f32::INFINITY, StereoLayout::OverUnder).is_none());
+        let shutter = Box::new(ShutterCamera::new(stereo(), 0.0, 1.0));
+        assert!(StereoCamera::new(shutter, 0.2, f32::INFINITY, StereoLayout::OverUnder).is_none());
+        let prepared = stereo().prepare(200, 100, &HitableList::from_vec(vec![]));
+        assert!(StereoCamera::new(prepared, 0.2, f32::INFINITY, StereoLayout::OverUnder).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "must be prepared")]
+    fn unprepared_stereo_camera_has_no_rays() {
+        let camera = EquirectangularCamera::look_at(Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0));
+        StereoCamera::new(Box::new(camera), 0.2, f32::INFINITY, StereoLayout::SideBySide).unwrap().ray(0.5, 0.5);
+    }
+}
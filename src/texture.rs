@@ -0,0 +1,299 @@
+use crate::geometry::Vec3;
+use crate::hitable::HitRecord;
+use crate::image::Image;
+use std::sync::Arc;
+
+/// Material parameter varying over surfaces, looked up by texture coordinates `(u, v)`
+/// or directly by the position of the hit point.
+///
+/// Colors are returned as they are, while parameters given by a single number,
+/// like roughness, read the average of the three channels.
+pub trait Texture: Send + Sync {
+    /// `footprint` is the width of the area to average around `(u, v)`, in texture coordinates.
+    fn value(&self, u: f32, v: f32, footprint: f32, point: Vec3) -> Vec3;
+}
+
+impl dyn Texture {
+    /// Color of the texture at the hit point.
+    pub fn at(&self, hit_record: &HitRecord) -> Vec3 {
+        self.value(hit_record.u, hit_record.v, hit_record.footprint, hit_record.point)
+    }
+
+    /// Single number parameter at the hit point.
+    pub fn scalar_at(&self, hit_record: &HitRecord) -> f32 {
+        let value = self.at(hit_record);
+        (value.x() + value.y() + value.z()) / 3.0
+    }
+}
+
+/// Same value everywhere.
+pub struct ConstantTexture {
+    pub color: Vec3,
+}
+
+impl ConstantTexture {
+    pub fn new(color: Vec3) -> Self {
+        Self { color }
+    }
+
+    /// Texture for a single number parameter.
+    pub fn gray(value: f32) -> Self {
+        Self::new(Vec3::new(value, value, value))
+    }
+}
+
+impl Texture for ConstantTexture {
+    fn value(&self, _u: f32, _v: f32, _footprint: f32, _point: Vec3) -> Vec3 {
+        self.color
+    }
+}
+
+/// Checkerboard of cubes with edges of `size`, filled alternately by `even` and `odd`.
+/// It is solid, so that it does not depend on texture coordinates and does not distort
+/// towards poles of spheres.
+pub struct CheckerTexture {
+    pub even: Arc<dyn Texture>,
+    pub odd: Arc<dyn Texture>,
+    pub size: f32,
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: f32, v: f32, footprint: f32, point: Vec3) -> Vec3 {
+        let cell = point / self.size;
+        let parity = cell.x().floor() as i64 + cell.y().floor() as i64 + cell.z().floor() as i64;
+        if parity.rem_euclid(2) == 0 {
+            self.even.value(u, v, footprint, point)
+        } else {
+            self.odd.value(u, v, footprint, point)
+        }
+    }
+}
+
+/// Linear blend from `start` at `v = 0` to `end` at `v = 1`.
+pub struct GradientTexture {
+    pub start: Vec3,
+    pub end: Vec3,
+}
+
+impl Texture for GradientTexture {
+    fn value(&self, _u: f32, v: f32, _footprint: f32, _point: Vec3) -> Vec3 {
+        let v = v.clamp(0.0, 1.0);
+        (1.0 - v) * self.start + v * self.end
+    }
+}
+
+/// How texels around a point are combined into the value of an image texture.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextureFilter {
+    /// The texel the point falls in.
+    Nearest,
+    /// Linear interpolation between the four texels nearest to the point.
+    Bilinear,
+    /// Bilinear lookups in the two mipmap levels whose texels are closest in size to the footprint,
+    /// blended linearly. Avoids aliasing of textures seen from afar.
+    Trilinear,
+}
+
+impl TextureFilter {
+    pub fn from_name(name: &str) -> Option<TextureFilter> {
+        match name {
+            "nearest" => Some(TextureFilter::Nearest),
+            "bilinear" => Some(TextureFilter::Bilinear),
+            "trilinear" => Some(TextureFilter::Trilinear),
+            _ => None,
+        }
+    }
+}
+
+/// How texture coordinates outside of the unit square map into an image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WrapMode {
+    /// The image tiles the plane.
+    Repeat,
+    /// Edges of the image extend indefinitely.
+    Clamp,
+    /// The image tiles the plane, flipped in every other tile, so that the tiles meet seamlessly.
+    Mirror,
+}
+
+impl WrapMode {
+    pub fn from_name(name: &str) -> Option<WrapMode> {
+        match name {
+            "repeat" => Some(WrapMode::Repeat),
+            "clamp" => Some(WrapMode::Clamp),
+            "mirror" => Some(WrapMode::Mirror),
+            _ => None,
+        }
+    }
+
+    /// Index of a texel within `size` texels.
+    fn apply(self, i: i64, size: usize) -> usize {
+        let size = size as i64;
+        let i = match self {
+            WrapMode::Repeat => i.rem_euclid(size),
+            WrapMode::Clamp => i.clamp(0, size - 1),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2 * size);
+                if i < size { i } else { 2 * size - 1 - i }
+            }
+        };
+        i as usize
+    }
+}
+
+/// Image stretched over the unit square of texture coordinates, with `v = 0` at the bottom row.
+pub struct ImageTexture {
+    /// Mipmap levels from the full image down to a single texel, each half the size of the previous one.
+    /// Only the full image is kept unless filtering is trilinear.
+    levels: Vec<Image>,
+    filter: TextureFilter,
+    wrap: WrapMode,
+}
+
+impl ImageTexture {
+    pub fn new(image: Image, filter: TextureFilter, wrap: WrapMode) -> Self {
+        let mut levels = vec![image];
+        if filter == TextureFilter::Trilinear {
+            while let Some(level) = downsample(levels.last().unwrap()) {
+                levels.push(level);
+            }
+        }
+        Self { levels, filter, wrap }
+    }
+
+    pub fn image(&self) -> &Image {
+        &self.levels[0]
+    }
+
+    fn texel(&self, level: &Image, x: i64, y: i64) -> Vec3 {
+        level.pixel(self.wrap.apply(x, level.width()), self.wrap.apply(y, level.height()))
+    }
+
+    fn nearest(&self, level: &Image, u: f32, v: f32) -> Vec3 {
+        let x = (u * level.width() as f32).floor() as i64;
+        let y = ((1.0 - v) * level.height() as f32).floor() as i64;
+        self.texel(level, x, y)
+    }
+
+    fn bilinear(&self, level: &Image, u: f32, v: f32) -> Vec3 {
+        // Texel centers lie at half-integer coordinates.
+        let x = u * level.width() as f32 - 0.5;
+        let y = (1.0 - v) * level.height() as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = (1.0 - tx) * self.texel(level, x0, y0) + tx * self.texel(level, x0 + 1, y0);
+        let bottom = (1.0 - tx) * self.texel(level, x0, y0 + 1) + tx * self.texel(level, x0 + 1, y0 + 1);
+        (1.0 - ty) * top + ty * bottom
+    }
+}
+
+/// Next mipmap level averaging blocks of two by two texels, none for a single texel.
+/// Odd rows and columns at the end of the image are averaged into the last texel.
+fn downsample(image: &Image) -> Option<Image> {
+    let (width, height) = (image.width(), image.height());
+    if width <= 1 && height <= 1 {
+        return None;
+    }
+    let (half_width, half_height) = ((width / 2).max(1), (height / 2).max(1));
+    let mut pixels = Vec::with_capacity(half_width * half_height);
+    for y in 0..half_height {
+        let rows = if y + 1 == half_height { 2 * y..height } else { 2 * y..2 * y + 2 };
+        for x in 0..half_width {
+            let columns = if x + 1 == half_width { 2 * x..width } else { 2 * x..2 * x + 2 };
+            let mut sum = Vec3::zeros();
+            let mut count = 0;
+            for row in rows.clone() {
+                for column in columns.clone() {
+                    sum = sum + image.pixel(column, row);
+                    count += 1;
+                }
+            }
+            pixels.push(sum / count as f32);
+        }
+    }
+    Some(Image::from_pixels(half_width, half_height, pixels))
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f32, v: f32, footprint: f32, _point: Vec3) -> Vec3 {
+        let image = &self.levels[0];
+        if image.width() == 0 || image.height() == 0 || !(u.is_finite() && v.is_finite()) {
+            return Vec3::zeros();
+        }
+        match self.filter {
+            TextureFilter::Nearest => self.nearest(image, u, v),
+            TextureFilter::Bilinear => self.bilinear(image, u, v),
+            TextureFilter::Trilinear => {
+                // Level whose texels are as wide as the footprint.
+                let texels = footprint * image.width().max(image.height()) as f32;
+                let level = if texels > 1.0 { texels.log2().min((self.levels.len() - 1) as f32) } else { 0.0 };
+                let lower = level.floor() as usize;
+                let t = level - lower as f32;
+                let value = self.bilinear(&self.levels[lower], u, v);
+                if t > 0.0 {
+                    (1.0 - t) * value + t * self.bilinear(&self.levels[lower + 1], u, v)
+                } else {
+                    value
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checker_alternates_between_cells() {
+        let checker = CheckerTexture {
+            even: Arc::new(ConstantTexture::gray(1.0)),
+            odd: Arc::new(ConstantTexture::gray(0.0)),
+            size: 0.5,
+        };
+        let at = |x, y, z| checker.value(0.0, 0.0, 0.0, Vec3::new(x, y, z)).x();
+        assert_eq!(at(0.1, 0.1, 0.1), 1.0);
+        assert_eq!(at(0.6, 0.1, 0.1), 0.0);
+        assert_eq!(at(-0.1, 0.1, 0.1), 0.0);
+        assert_eq!(at(-0.1, -0.1, 0.1), 1.0);
+    }
+
+    fn colors() -> Image {
+        let pixels = vec![Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0), Vec3::zeros()];
+        Image::from_pixels(2, 2, pixels)
+    }
+
+    #[test]
+    fn image_has_bottom_row_at_v_zero_and_wraps() {
+        let value = |wrap, u, v| ImageTexture::new(colors(), TextureFilter::Nearest, wrap).value(u, v, 0.0, Vec3::zeros()).raw;
+        assert_eq!(value(WrapMode::Repeat, 0.25, 0.75), [1.0, 0.0, 0.0]);
+        assert_eq!(value(WrapMode::Repeat, 0.75, 0.75), [0.0, 1.0, 0.0]);
+        assert_eq!(value(WrapMode::Repeat, 0.25, 0.25), [0.0, 0.0, 1.0]);
+        assert_eq!(value(WrapMode::Repeat, -0.75, 1.25), [0.0, 0.0, 1.0]);
+        assert_eq!(value(WrapMode::Clamp, -0.75, 1.25), [1.0, 0.0, 0.0]);
+        assert_eq!(value(WrapMode::Clamp, 3.0, -2.0), [0.0, 0.0, 0.0]);
+        assert_eq!(value(WrapMode::Mirror, 1.25, 0.75), [0.0, 1.0, 0.0]);
+        assert_eq!(value(WrapMode::Mirror, -0.25, 0.75), [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn filters_blend_texels_and_mipmap_levels() {
+        let bilinear = ImageTexture::new(colors(), TextureFilter::Bilinear, WrapMode::Clamp);
+        assert_eq!(bilinear.value(0.5, 0.75, 0.0, Vec3::zeros()).raw, [0.5, 0.5, 0.0]);
+        assert_eq!(bilinear.value(0.1, 0.9, 0.0, Vec3::zeros()).raw, [1.0, 0.0, 0.0]);
+
+        let trilinear = ImageTexture::new(colors(), TextureFilter::Trilinear, WrapMode::Repeat);
+        let average = [0.25, 0.25, 0.25];
+        // Footprints spanning the whole image read its average, small ones the image itself.
+        assert_eq!(trilinear.value(0.25, 0.75, 1.0, Vec3::zeros()).raw, average);
+        assert_eq!(trilinear.value(0.25, 0.75, 8.0, Vec3::zeros()).raw, average);
+        let sharp = bilinear.value(0.3, 0.7, 0.0, Vec3::zeros()).raw;
+        let mip = ImageTexture::new(colors(), TextureFilter::Trilinear, WrapMode::Clamp);
+        assert_eq!(mip.value(0.3, 0.7, 0.1, Vec3::zeros()).raw, sharp);
+        let halfway = mip.value(0.3, 0.7, 0.5f32.sqrt(), Vec3::zeros());
+        for (value, (sharp, average)) in halfway.raw.iter().zip(sharp.iter().zip(&average)) {
+            assert!((value - 0.5 * (sharp + average)).abs() < 1e-6);
+        }
+    }
+}
@@ -2,18 +2,20 @@ use crate::hitable::{Hitable, HitRecord};
 use crate::ray::Ray;
 use crate::geometry::Vec3;
 use crate::material::Material;
-use crate::mesh::{Mesh, box_intersect};
+use crate::mesh::Mesh;
+use crate::aabb::Aabb;
 use std::sync::Arc;
 
-pub(crate) struct TriangulatedModel {
-    pub(crate) mesh: Mesh,
-    pub(crate) material: Arc<dyn Material>,
+pub struct TriangulatedModel {
+    /// Shared with the light sampling the mesh when its material emits light.
+    pub mesh: Arc<Mesh>,
+    pub material: Arc<dyn Material>,
 }
 
 impl TriangulatedModel {
-    pub(crate) fn new(mesh: Mesh, material: Arc<dyn Material>) -> TriangulatedModel {
+    pub fn new(mesh: Mesh, material: Arc<dyn Material>) -> TriangulatedModel {
         Self {
-            mesh,
+            mesh: Arc::new(mesh),
             material,
         }
     }
@@ -21,83 +23,400 @@ impl TriangulatedModel {
 
 impl Hitable for TriangulatedModel {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if !box_intersect(ray, &self.mesh.aabb()) {
-            return None;
-        }
+        let mut closest = None;
 
-        for (i, (v0, v1, v2)) in self.mesh.iter_triangles().enumerate() {
-            if let Some(t) = ray_triangle_intersect(ray, v0.position, v1.position, v2.position) {
-                if !(t > t_min && t < t_max) {
-                    continue;
-                }
-                return Some(HitRecord {
-                    t,
-                    point: ray.point_at_parameter(t),
-                    normal: (v0.normal + v1.normal + v2.normal) / 3.0,
-                    material: self.material.clone(),
-                });
+        self.mesh.bvh().traverse(ray, t_min, t_max, |i, closest_so_far| {
+            let (v0, v1, v2) = self.mesh.triangle(i);
+            let hit = ray_triangle_intersect(ray, v0.position, v1.position, v2.position)?;
+            if !(hit.t > t_min && hit.t < closest_so_far) {
+                return None;
             }
-        }
-        None
+            closest = Some((i, hit));
+            Some(hit.t)
+        });
+
+        let (i, hit) = closest?;
+        let (v0, v1, v2) = self.mesh.triangle(i);
+        let cross = Vec3::cross(v1.position - v0.position, v2.position - v0.position);
+        let geometric_normal = cross.normalize();
+        let normal = match (v0.normal, v1.normal, v2.normal) {
+            (Some(n0), Some(n1), Some(n2)) => {
+                let n = hit.interpolate(n0, n1, n2);
+                if n.squared_len() > 0.0 { n.normalize() } else { geometric_normal }
+            }
+            _ => geometric_normal,
+        };
+        let (u, v) = hit.interpolate_uv(v0.uv, v1.uv, v2.uv);
+        // Texture coordinates stretch over the triangle by the square root of the ratio of areas.
+        let (a, b) = ((v1.uv.0 - v0.uv.0, v1.uv.1 - v0.uv.1), (v2.uv.0 - v0.uv.0, v2.uv.1 - v0.uv.1));
+        let uv_area = (a.0 * b.1 - a.1 * b.0).abs();
+        let area = cross.length();
+        let scale = if area > 0.0 { (uv_area / area).sqrt() } else { 0.0 };
+        Some(HitRecord {
+            t: hit.t,
+            point: hit.interpolate(v0.position, v1.position, v2.position),
+            normal,
+            u,
+            v,
+            footprint: scale * ray.width_at_parameter(hit.t),
+            material: self.material.clone(),
+        })
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.mesh.bvh().traverse_any(ray, t_min, t_max, |i| {
+            let (v0, v1, v2) = self.mesh.triangle(i);
+            match ray_triangle_intersect(ray, v0.position, v1.position, v2.position) {
+                Some(hit) => hit.t > t_min && hit.t < t_max,
+                None => false,
+            }
+        })
     }
+
+    fn bounding_box(&self) -> Aabb {
+        self.mesh.aabb()
+    }
+}
+
+
+/// Intersection of a ray with a triangle.
+#[derive(Copy, Clone, Debug)]
+pub struct TriangleHit {
+    pub t: f32,
+    /// Barycentric weights of the hit point with respect to the first, second and third vertex.
+    pub barycentric: [f32; 3],
 }
 
+impl TriangleHit {
+    pub fn interpolate(&self, a0: Vec3, a1: Vec3, a2: Vec3) -> Vec3 {
+        let [b0, b1, b2] = self.barycentric;
+        b0 * a0 + b1 * a1 + b2 * a2
+    }
 
-fn ray_triangle_intersect(ray: &Ray, v0: Vec3, v1: Vec3, v2: Vec3) -> Option<f32> {
-    // Algorithm based on
-    // https://www.scratchapixel.com/lessons/3d-basic-rendering/ray-tracing-rendering-a-triangle/ray-triangle-intersection-geometric-solution
+    pub fn interpolate_uv(&self, t0: (f32, f32), t1: (f32, f32), t2: (f32, f32)) -> (f32, f32) {
+        let [b0, b1, b2] = self.barycentric;
+        (b0 * t0.0 + b1 * t1.0 + b2 * t2.0, b0 * t0.1 + b1 * t1.1 + b2 * t2.1)
+    }
+}
 
-    // compute plane's normal
-    let v0v1 = v1 - v0;
-    let v0v2 = v2 - v0;
-    let n = Vec3::cross(v0v1, v0v2);
+pub fn ray_triangle_intersect(ray: &Ray, v0: Vec3, v1: Vec3, v2: Vec3) -> Option<TriangleHit> {
+    // Watertight algorithm based on
+    // Woop, Benthin, Wald: Watertight Ray/Triangle Intersection, JCGT 2013
+    // http://jcgt.org/published/0002/01/05/
 
-    // Step 1: find p
-    // check if ray and plane are parallel?
-    let n_dot_ray_direction = Vec3::dot(n, ray.direction);
-    if n_dot_ray_direction.abs() < std::f32::EPSILON {
-        return None; // parallel so they do not intersect
+    // Pick the dominant axis of the ray direction as z and keep winding of the other two
+    let d = ray.direction.raw;
+    let kz = if d[0].abs() > d[1].abs() {
+        if d[0].abs() > d[2].abs() { 0 } else { 2 }
+    } else if d[1].abs() > d[2].abs() { 1 } else { 2 };
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    if d[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
     }
 
-    // compute d parameter using equation 2
-    let d = Vec3::dot(n, v0);
+    // Shear transformation which aligns the ray with the z axis
+    let sx = d[kx] / d[kz];
+    let sy = d[ky] / d[kz];
+    let sz = 1.0 / d[kz];
+
+    let a = (v0 - ray.origin).raw;
+    let b = (v1 - ray.origin).raw;
+    let c = (v2 - ray.origin).raw;
+
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    // Scaled barycentric coordinates, i.e. signed areas of the sub-triangles in the 2D projection
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+
+    // Exactly zero may be a rounding artifact, so recompute the edge functions in double precision
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        u = (f64::from(cx) * f64::from(by) - f64::from(cy) * f64::from(bx)) as f32;
+        v = (f64::from(ax) * f64::from(cy) - f64::from(ay) * f64::from(cx)) as f32;
+        w = (f64::from(bx) * f64::from(ay) - f64::from(by) * f64::from(ax)) as f32;
+    }
 
-    // compute t (eq. 3)
-    let t = (Vec3::dot(n, ray.origin) + d) / n_dot_ray_direction;
+    // Hit points lying exactly on an edge count as inside for both neighbouring triangles
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    // Zero for degenerate triangles and rays lying in the plane of the triangle
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+
+    let az = sz * a[kz];
+    let bz = sz * b[kz];
+    let cz = sz * c[kz];
+    let t = (u * az + v * bz + w * cz) / det;
     // check if the triangle is in behind the ray
     if t < 0.0 {
         return None;
     }
 
-    // compute the intersection point using eq. 1
-    let p = ray.origin + t * ray.direction;
+    Some(TriangleHit {
+        t,
+        barycentric: [u / det, v / det, w / det],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::MeshBuilder;
+    use crate::material::Lambertian;
+    use crate::texture::ConstantTexture;
+    use crate::ray::RayCone;
+
+    /// Builds a model of triangles parallel to the XY plane, placed at the given depths in that order.
+    fn stacked_triangles(depths: &[f32]) -> TriangulatedModel {
+        let mut builder = MeshBuilder::new();
+        let n = builder.push_normal(Vec3::new(0.0, 0.0, 1.0));
+        for &z in depths {
+            let v0 = builder.push_vertex(Vec3::new(-1.0, -1.0, z));
+            let v1 = builder.push_vertex(Vec3::new(1.0, -1.0, z));
+            let v2 = builder.push_vertex(Vec3::new(0.0, 1.0, z));
+            builder.push_face(v0, n, v1, n, v2, n);
+        }
+        TriangulatedModel::new(
+            builder.build(),
+            Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.5)) }),
+        )
+    }
+
+    fn towards_negative_z() -> Ray {
+        Ray::new(Vec3::new(0.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0))
+    }
+
+    #[test]
+    fn returns_nearest_triangle_regardless_of_order() {
+        let back_first = stacked_triangles(&[-1.0, 1.0]);
+        let front_first = stacked_triangles(&[1.0, -1.0]);
+
+        for model in &[back_first, front_first] {
+            let record = model.hit(&towards_negative_z(), 0.001, f32::INFINITY).unwrap();
+            assert!((record.t - 9.0).abs() < 1e-5);
+            assert!((record.point.z() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn returns_nearest_from_the_other_side() {
+        let model = stacked_triangles(&[-1.0, 1.0]);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -10.0), Vec3::new(0.0, 0.0, 1.0));
+
+        let record = model.hit(&ray, 0.001, f32::INFINITY).unwrap();
+        assert!((record.point.z() + 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn returns_nearest_among_many_shuffled_triangles() {
+        let depths: Vec<f32> = (0..200).map(|i| ((i * 37) % 200) as f32 * -0.1).collect();
+        let model = stacked_triangles(&depths);
+
+        let record = model.hit(&towards_negative_z(), 0.001, f32::INFINITY).unwrap();
+        assert!((record.t - 10.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn respects_ray_interval() {
+        let model = stacked_triangles(&[-1.0, 1.0]);
+        let ray = towards_negative_z();
+
+        let record = model.hit(&ray, 9.5, f32::INFINITY).unwrap();
+        assert!((record.t - 11.0).abs() < 1e-5);
+        assert!(model.hit(&ray, 0.001, 8.5).is_none());
+        assert!(model.hit(&ray, 9.5, 10.5).is_none());
+    }
+
+    #[test]
+    fn any_hit_agrees_with_closest_hit() {
+        let model = stacked_triangles(&[-1.0, 1.0]);
+        let ray = towards_negative_z();
+
+        assert!(model.hit_any(&ray, 0.001, f32::INFINITY));
+        assert!(model.hit_any(&ray, 9.5, f32::INFINITY));
+        assert!(!model.hit_any(&ray, 0.001, 8.5));
+        assert!(!model.hit_any(&ray, 9.5, 10.5));
+
+        let miss = Ray::new(Vec3::new(5.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(!model.hit_any(&miss, 0.001, f32::INFINITY));
+        assert!(model.hit(&miss, 0.001, f32::INFINITY).is_none());
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
 
-    // Step 2: inside-out test
-    // c - vector perpendicular to triangle's plane
+    #[test]
+    fn intersection_returns_distance_and_barycentrics() {
+        let (v0, v1, v2) = (Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let ray = Ray::new(Vec3::new(0.25, 0.5, 2.0), Vec3::new(0.0, 0.0, -1.0));
 
-    // edge 0
-    let edge0 = v1 - v0;
-    let vp0 = p - v0;
-    let c = Vec3::cross(edge0, vp0);
-    if Vec3::dot(n, c) < 0.0 {
-        return None; // p is on the right side
+        let hit = ray_triangle_intersect(&ray, v0, v1, v2).unwrap();
+        assert_close(hit.t, 2.0);
+        let [b0, b1, b2] = hit.barycentric;
+        assert_close(b0, 0.25);
+        assert_close(b1, 0.25);
+        assert_close(b2, 0.5);
+
+        let p = hit.interpolate(v0, v1, v2);
+        assert_close(p.x(), 0.25);
+        assert_close(p.y(), 0.5);
+        assert_close(p.z(), 0.0);
     }
 
-    // edge 1
-    let edge1 = v2 - v1;
-    let vp1 = p - v1;
-    let c = Vec3::cross(edge1, vp1);
-    if Vec3::dot(n, c) < 0.0 {
-        return None; // p is on the right side
+    #[test]
+    fn intersection_ignores_winding_and_rejects_triangles_behind() {
+        let (v0, v1, v2) = (Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let ray = Ray::new(Vec3::new(0.2, 0.2, 2.0), Vec3::new(0.0, 0.0, -1.0));
+
+        assert!(ray_triangle_intersect(&ray, v0, v2, v1).is_some());
+
+        let away = Ray::new(Vec3::new(0.2, 0.2, 2.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(ray_triangle_intersect(&away, v0, v1, v2).is_none());
     }
 
-    // edge 2
-    let edge2 = v0 - v2;
-    let vp2 = p - v2;
-    let c = Vec3::cross(edge2, vp2);
-    if Vec3::dot(n, c) < 0.0 {
-        return None; // p is on the right side
+    #[test]
+    fn intersection_includes_edges_and_vertices() {
+        let (v0, v1, v2) = (Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let origins = [
+            Vec3::new(0.5, 0.0, 1.0),
+            Vec3::new(0.5, 0.5, 1.0),
+            Vec3::new(0.0, 0.5, 1.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(0.0, 1.0, 1.0),
+        ];
+        for &origin in &origins {
+            let ray = Ray::new(origin, Vec3::new(0.0, 0.0, -1.0));
+            let hit = ray_triangle_intersect(&ray, v0, v1, v2);
+            assert!(hit.is_some(), "missed at {:?}", origin);
+            assert_close(hit.unwrap().t, 1.0);
+        }
+
+        let outside = Ray::new(Vec3::new(0.5, -1e-4, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(ray_triangle_intersect(&outside, v0, v1, v2).is_none());
     }
 
-    Some(t)
+    #[test]
+    fn no_leaks_through_shared_edge() {
+        // Two triangles sharing a diagonal, far from the origin and with an oblique ray, where
+        // rounding errors used to make rays slip between them.
+        let offset = Vec3::new(1234.5, -987.25, 321.0);
+        let q0 = offset + Vec3::new(0.0, 0.0, 0.0);
+        let q1 = offset + Vec3::new(0.3, 0.0, 0.1);
+        let q2 = offset + Vec3::new(0.3, 0.7, 0.2);
+        let q3 = offset + Vec3::new(0.0, 0.7, 0.1);
+        let direction = Vec3::new(0.13, 0.37, -1.0);
+
+        // Endpoints are corners of the quad, where any rounding of the origin lets the ray pass outside.
+        for i in 1..1000 {
+            let s = i as f32 / 1000.0;
+            let on_diagonal = q0 + s * (q2 - q0);
+            let ray = Ray::new(on_diagonal - 10.0 * direction, direction);
+            let first = ray_triangle_intersect(&ray, q0, q1, q2);
+            let second = ray_triangle_intersect(&ray, q0, q2, q3);
+            assert!(first.is_some() || second.is_some(), "leaked at s = {}", s);
+        }
+    }
+
+    #[test]
+    fn intersection_rejects_degenerate_triangles() {
+        let ray = Ray::new(Vec3::new(0.5, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let collinear = (Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.5, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(ray_triangle_intersect(&ray, collinear.0, collinear.1, collinear.2).is_none());
+
+        let point = Vec3::new(0.5, 0.0, 0.0);
+        assert!(ray_triangle_intersect(&ray, point, point, point).is_none());
+    }
+
+    #[test]
+    fn intersection_rejects_grazing_rays() {
+        let (v0, v1, v2) = (Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+
+        let in_plane = Ray::new(Vec3::new(-1.0, 0.2, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(ray_triangle_intersect(&in_plane, v0, v1, v2).is_none());
+
+        let above_plane = Ray::new(Vec3::new(-1.0, 0.2, 1e-3), Vec3::new(1.0, 0.0, 0.0));
+        assert!(ray_triangle_intersect(&above_plane, v0, v1, v2).is_none());
+
+        let nearly_parallel = Ray::new(Vec3::new(-0.2, 0.2, 1e-3), Vec3::new(1.0, 0.0, -2e-3));
+        let hit = ray_triangle_intersect(&nearly_parallel, v0, v1, v2).unwrap();
+        assert!((hit.t - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn interpolates_vertex_normals() {
+        let mut builder = MeshBuilder::new();
+        let v0 = builder.push_vertex(Vec3::new(0.0, 0.0, 0.0));
+        let v1 = builder.push_vertex(Vec3::new(1.0, 0.0, 0.0));
+        let v2 = builder.push_vertex(Vec3::new(0.0, 1.0, 0.0));
+        let n0 = builder.push_normal(Vec3::new(0.0, 0.0, 1.0));
+        let n1 = builder.push_normal(Vec3::new(1.0, 0.0, 0.0));
+        builder.push_face(v0, n0, v1, n1, v2, n0);
+        let model = TriangulatedModel::new(builder.build(), Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.0)) }));
+
+        let ray = Ray::new(Vec3::new(0.5, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let normal = model.hit(&ray, 0.001, f32::INFINITY).unwrap().normal;
+        let expected = Vec3::new(1.0, 0.0, 1.0).normalize();
+        assert_close(normal.x(), expected.x());
+        assert_close(normal.y(), expected.y());
+        assert_close(normal.z(), expected.z());
+    }
+
+    #[test]
+    fn falls_back_to_geometric_normal() {
+        let mut builder = MeshBuilder::new();
+        let v0 = builder.push_vertex(Vec3::new(0.0, 0.0, 0.0));
+        let v1 = builder.push_vertex(Vec3::new(2.0, 0.0, 0.0));
+        let v2 = builder.push_vertex(Vec3::new(0.0, 2.0, 0.0));
+        builder.push_triangle(v0, v1, v2);
+        let model = TriangulatedModel::new(builder.build(), Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.0)) }));
+
+        let ray = Ray::new(Vec3::new(0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let normal = model.hit(&ray, 0.001, f32::INFINITY).unwrap().normal;
+        assert_close(normal.x(), 0.0);
+        assert_close(normal.y(), 0.0);
+        assert_close(normal.z(), 1.0);
+    }
+
+    #[test]
+    fn interpolates_texture_coordinates() {
+        let mut builder = MeshBuilder::new();
+        let v0 = builder.push_vertex(Vec3::new(0.0, 0.0, 0.0));
+        let v1 = builder.push_vertex(Vec3::new(2.0, 0.0, 0.0));
+        let v2 = builder.push_vertex(Vec3::new(0.0, 2.0, 0.0));
+        let v3 = builder.push_vertex(Vec3::new(0.0, 0.0, -1.0));
+        let v4 = builder.push_vertex(Vec3::new(2.0, 0.0, -1.0));
+        let v5 = builder.push_vertex(Vec3::new(0.0, 2.0, -1.0));
+        let t0 = builder.push_uv(0.1, 0.2);
+        let t1 = builder.push_uv(0.5, 0.2);
+        let t2 = builder.push_uv(0.1, 0.8);
+        builder.push_triangle(v0, v1, v2);
+        builder.set_face_uvs(t0, t1, t2).unwrap();
+        builder.push_triangle(v3, v4, v5);
+        let model = TriangulatedModel::new(builder.build(), Arc::new(Lambertian { albedo: Arc::new(ConstantTexture::gray(0.0)) }));
+
+        let ray = Ray { cone: RayCone { width: 1.0, spread: 0.0 }, ..Ray::new(Vec3::new(0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0)) };
+        let record = model.hit(&ray, 0.001, f32::INFINITY).unwrap();
+        assert_close(record.u, 0.2);
+        assert_close(record.v, 0.35);
+        // The face covers 0.12 units of texture area with 2 units of its own.
+        assert_close(record.footprint, (0.12f32 / 2.0).sqrt());
+
+        // Faces without texture coordinates span the default triangle.
+        let record = model.hit(&ray, 1.5, f32::INFINITY).unwrap();
+        assert_close(record.u, 0.5);
+        assert_close(record.v, 0.25);
+    }
 }